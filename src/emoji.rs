@@ -0,0 +1,86 @@
+//! `:shortcode:` to unicode emoji replacement for text bodies.
+
+// most used GitHub/Slack shortcodes, sorted by name for binary search
+const SHORTCODES: [(&str, &str); 48] = [
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("beer", "🍺"),
+    ("bug", "🐛"),
+    ("clap", "👏"),
+    ("coffee", "☕"),
+    ("confused", "😕"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("grin", "😁"),
+    ("grinning", "😀"),
+    ("heart", "❤️"),
+    ("heavy_check_mark", "✔️"),
+    ("hourglass", "⌛"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("memo", "📝"),
+    ("muscle", "💪"),
+    ("ok_hand", "👌"),
+    ("party_popper", "🎉"),
+    ("point_right", "👉"),
+    ("pray", "🙏"),
+    ("question", "❓"),
+    ("raised_hands", "🙌"),
+    ("rocket", "🚀"),
+    ("rofl", "🤣"),
+    ("scream", "😱"),
+    ("see_no_evil", "🙈"),
+    ("shrug", "🤷"),
+    ("slightly_smiling_face", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("sunglasses", "😎"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("warning", "⚠️"),
+    ("wave", "👋"),
+    ("white_check_mark", "✅"),
+    ("wink", "😉"),
+    ("wrench", "🔧"),
+    ("x", "❌"),
+];
+
+fn is_shortcode_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || matches!(ch, '_' | '+' | '-')
+}
+
+/// Replaces known `:shortcode:` with emoji, unknown ones are kept verbatim.
+pub fn replace_shortcodes(text: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let name_len = after
+            .find(|ch: char| !is_shortcode_char(ch))
+            .unwrap_or(after.len());
+        let emoji = after[name_len..]
+            .starts_with(':')
+            .then(|| SHORTCODES.binary_search_by_key(&&after[..name_len], |(name, _)| name))
+            .and_then(Result::ok);
+        match emoji {
+            Some(index) => {
+                result.push_str(SHORTCODES[index].1);
+                rest = &after[name_len + 1..];
+            }
+            None => {
+                result.push(':');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
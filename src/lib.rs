@@ -0,0 +1,3048 @@
+//! Email enrichment used by the `enrichmail` mutt helper.
+//!
+//! Takes a parsed [`mail_parser::Message`] and rebuilds it with
+//! [`mail_builder::MessageBuilder`], optionally adding html body generated
+//! from the markdown in the text part.
+//!
+//! ```
+//! use enrichmail::{get_builder_from_parser, text_body_as_html, HtmlOptions, RebuildOptions};
+//! use mail_parser::Message;
+//!
+//! let raw = b"From: john@example.com\r\nSubject: Hi\r\n\r\nHello *world*\r\n";
+//! let message = Message::parse(raw).unwrap();
+//!
+//! let html = text_body_as_html(&message, None, &HtmlOptions::default()).unwrap();
+//! let eml = get_builder_from_parser(&message, &RebuildOptions::default()).html_body(html);
+//!
+//! let output = eml.write_to_string().unwrap();
+//! assert!(output.contains("<em>world</em>"));
+//! ```
+
+mod emoji;
+mod org;
+
+use base64::{engine::general_purpose, Engine as _};
+use comrak::{markdown_to_html, ComrakOptions};
+use mail_builder::encoders::base64::base64_encode_mime;
+use mail_builder::encoders::encode::rfc2047_encode;
+use mail_builder::encoders::quoted_printable::quoted_printable_encode;
+use mail_builder::headers as b_headers;
+use mail_builder::headers::HeaderType;
+use mail_builder::mime::{make_boundary, BodyPart, MimePart};
+use mail_builder::MessageBuilder;
+use mail_parser::decoders::base64::base64_decode;
+use mail_parser::decoders::quoted_printable::quoted_printable_decode;
+use mail_parser::{
+    Addr, DateTime, Encoding, Group, Header, HeaderName, HeaderValue, Message, MessagePart,
+    MimeHeaders, PartType, RfcHeader,
+};
+use regex::Regex;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// Returns the identifier part of `List-Id` header.
+pub fn get_list_id(message: &Message) -> Option<String> {
+    match message.list_id() {
+        // "Description <list.id>" form gets parsed as an address
+        HeaderValue::Address(address) => address.address.as_ref().map(|id| id.to_string()),
+        HeaderValue::Text(text) => {
+            let id = match (text.rfind('<'), text.rfind('>')) {
+                (Some(start), Some(end)) if start < end => &text[start + 1..end],
+                _ => text.as_ref(),
+            };
+            Some(id.trim().to_owned())
+        }
+        _ => None,
+    }
+}
+
+// first raw value of the header, name is matched case-insensitively
+fn raw_header<'a>(message: &'a Message, name: &str) -> Option<&'a str> {
+    message
+        .headers_raw()
+        .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+/// Returns conversation GUID from Outlook `Thread-Index` header as hex.
+pub fn get_thread_index(message: &Message) -> Option<String> {
+    let value = raw_header(message, "Thread-Index")?;
+    let encoded: String = value.split_whitespace().collect();
+    let decoded = general_purpose::STANDARD.decode(encoded).ok()?;
+    // 6 bytes of FILETIME come before the 16 bytes of GUID
+    let guid = decoded.get(6..22)?;
+    Some(guid.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Returns envelope sender as `<address>` from `Return-Path`, or from `From`
+/// when there is no Return-Path. Null sender of bounces is returned as `<>`.
+pub fn get_envelope_from(message: &Message) -> Option<String> {
+    let return_path = raw_header(message, "Return-Path").map(str::trim);
+    let address = match return_path {
+        Some(value) => match (value.find('<'), value.rfind('>')) {
+            (Some(start), Some(end)) if start < end => value[start + 1..end].trim().to_owned(),
+            _ => value.to_owned(),
+        },
+        None => header_addresses(message.from())
+            .first()?
+            .address
+            .as_deref()?
+            .to_owned(),
+    };
+    Some(format!("<{}>", address))
+}
+
+/// Returns `pass`, `fail` or `none` from `dmarc=` result of the topmost
+/// `Authentication-Results` header that has one.
+pub fn get_dmarc_result(message: &Message) -> &'static str {
+    let result = message
+        .headers_raw()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Authentication-Results"))
+        .find_map(|(_, value)| {
+            value
+                .split(|ch: char| ch == ';' || ch.is_whitespace())
+                .find_map(|token| token.strip_prefix("dmarc="))
+                .map(str::to_ascii_lowercase)
+        });
+    match result.as_deref() {
+        Some("pass") => "pass",
+        Some("fail") => "fail",
+        _ => "none",
+    }
+}
+
+/// Returns addresses from From, To, Cc, Bcc, Reply-To and Sender headers as
+/// `Name <email>`, first occurrence of each email wins.
+pub fn get_all_addresses(message: &Message) -> Vec<String> {
+    let mut addresses: Vec<&Addr> = Vec::new();
+    for value in [
+        message.from(),
+        message.to(),
+        message.cc(),
+        message.bcc(),
+        message.reply_to(),
+        message.sender(),
+    ] {
+        addresses.extend(header_addresses(value));
+    }
+    let mut seen: Vec<String> = Vec::new();
+    addresses
+        .iter()
+        .filter_map(|address| {
+            let email = address.address.as_ref()?;
+            if seen.iter().any(|known| known.eq_ignore_ascii_case(email)) {
+                return None;
+            }
+            seen.push(email.to_string());
+            format_address(address)
+        })
+        .collect()
+}
+
+// `Name <email>`, or just email when there is no name
+fn format_address(address: &Addr) -> Option<String> {
+    let email = address.address.as_ref()?;
+    Some(match address.name.as_ref() {
+        Some(name) => format!("{} <{}>", name, email),
+        None => email.to_string(),
+    })
+}
+
+/// Returns addresses from address header value, including group members.
+fn header_addresses<'x>(value: &'x HeaderValue<'x>) -> Vec<&'x Addr<'x>> {
+    match value {
+        HeaderValue::Address(address) => vec![address],
+        HeaderValue::AddressList(list) => list.iter().collect(),
+        HeaderValue::Group(group) => group.addresses.iter().collect(),
+        HeaderValue::GroupList(groups) => groups
+            .iter()
+            .flat_map(|group| group.addresses.iter())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Compares subject, from and attachment count of rebuilt message with the
+/// ones expected from original and rebuild options, returns description of
+/// every mismatch.
+pub fn verify_rebuild(original: &Message, rebuilt: &[u8], options: &RebuildOptions) -> Vec<String> {
+    let Some(rebuilt) = Message::parse(rebuilt) else {
+        return vec!["rebuilt message can not be parsed".to_string()];
+    };
+    let redact = options.redact.as_deref();
+    let expected_subject = if is_redacted_header("Subject", redact) {
+        REDACTED.to_owned()
+    } else {
+        let prefix = get_front_matter(original, &options.body).subject_prefix;
+        rebuilt_subject(original, prefix.as_deref(), options)
+    };
+    let from = |message: &Message, redact: bool| {
+        header_addresses(message.from())
+            .iter()
+            .filter_map(|address| address.address.as_deref())
+            .map(|email| {
+                if redact {
+                    redact_email(email)
+                } else {
+                    email.to_owned()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let fields = [
+        (
+            "subject",
+            expected_subject,
+            rebuilt.subject().unwrap_or("").to_string(),
+        ),
+        (
+            "from",
+            from(original, redact.is_some()),
+            from(&rebuilt, false),
+        ),
+        (
+            "attachments",
+            expected_attachment_count(original, options).to_string(),
+            rebuilt.attachment_count().to_string(),
+        ),
+    ];
+    fields
+        .iter()
+        .filter(|(_, expected, actual)| expected != actual)
+        .map(|(field, expected, actual)| format!("{}: - {:?} + {:?}", field, expected, actual))
+        .collect()
+}
+
+/// Returns `high`, `normal` or `low` from `X-Priority`, `Importance` or
+/// `Priority` header, `normal` when none of them is set.
+pub fn get_priority(message: &Message) -> &'static str {
+    ["X-Priority", "Importance", "Priority"]
+        .iter()
+        .find_map(|header| {
+            let value = raw_header(message, header)?;
+            // X-Priority comes as number with optional comment, eg. `1 (Highest)`
+            let value = value.split_whitespace().next()?.to_ascii_lowercase();
+            match value.as_str() {
+                "1" | "2" | "high" | "urgent" => Some("high"),
+                "3" | "normal" => Some("normal"),
+                "4" | "5" | "low" | "non-urgent" => Some("low"),
+                _ => None,
+            }
+        })
+        .unwrap_or("normal")
+}
+
+/// Returns domain mentioned in From display name when it does not match the
+/// From address domain, eg. `"paypal.com" <x@example.net>`.
+pub fn get_spoofed_domain(message: &Message) -> Option<String> {
+    let from = *header_addresses(message.from()).first()?;
+    let name = from.name.as_deref()?.to_ascii_lowercase();
+    let address_domain = from
+        .address
+        .as_deref()?
+        .rsplit_once('@')?
+        .1
+        .to_ascii_lowercase();
+    let is_related = |domain: &str| {
+        domain == address_domain
+            || address_domain.ends_with(&format!(".{}", domain))
+            || domain.ends_with(&format!(".{}", address_domain))
+    };
+    name.split(|ch: char| ch.is_whitespace() || matches!(ch, '<' | '>' | '(' | ')' | '"' | ','))
+        .filter_map(|word| {
+            let domain = word.rsplit_once('@').map_or(word, |(_, domain)| domain);
+            let domain = domain.trim_matches(|ch: char| !ch.is_ascii_alphanumeric());
+            // needs a dot and alphabetic top level part to count as domain
+            let (_, tld) = domain.rsplit_once('.')?;
+            (tld.len() > 1 && tld.chars().all(|ch| ch.is_ascii_alphabetic())).then_some(domain)
+        })
+        .find(|domain| !is_related(domain))
+        .map(str::to_owned)
+}
+
+/// Returns language tags declared in `Content-Language` header.
+pub fn get_content_languages(message: &Message) -> Vec<String> {
+    raw_header(message, "Content-Language").map_or_else(Vec::new, |value| {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_owned)
+            .collect()
+    })
+}
+
+/// Returns ISO 639-1 code of the text body language, `und` when body is empty
+/// or detection is not reliable.
+pub fn detect_language(message: &Message, options: &BodyOptions) -> &'static str {
+    let text = text_body(message, options);
+    whatlang::detect(&text)
+        .filter(whatlang::Info::is_reliable)
+        .and_then(|info| iso_639_1(info.lang().code()))
+        .unwrap_or("und")
+}
+
+// detector names languages with ISO 639-3 codes
+fn iso_639_1(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "afr" => "af",
+        "aka" => "ak",
+        "amh" => "am",
+        "ara" => "ar",
+        "aze" => "az",
+        "bel" => "be",
+        "ben" => "bn",
+        "bul" => "bg",
+        "cat" => "ca",
+        "ces" => "cs",
+        "cmn" => "zh",
+        "cym" => "cy",
+        "dan" => "da",
+        "deu" => "de",
+        "ell" => "el",
+        "eng" => "en",
+        "epo" => "eo",
+        "est" => "et",
+        "fin" => "fi",
+        "fra" => "fr",
+        "guj" => "gu",
+        "heb" => "he",
+        "hin" => "hi",
+        "hrv" => "hr",
+        "hun" => "hu",
+        "hye" => "hy",
+        "ind" => "id",
+        "ita" => "it",
+        "jav" => "jv",
+        "jpn" => "ja",
+        "kan" => "kn",
+        "kat" => "ka",
+        "khm" => "km",
+        "kor" => "ko",
+        "lat" => "la",
+        "lav" => "lv",
+        "lit" => "lt",
+        "mal" => "ml",
+        "mar" => "mr",
+        "mkd" => "mk",
+        "mya" => "my",
+        "nep" => "ne",
+        "nld" => "nl",
+        "nob" => "nb",
+        "ori" => "or",
+        "pan" => "pa",
+        "pes" => "fa",
+        "pol" => "pl",
+        "por" => "pt",
+        "ron" => "ro",
+        "rus" => "ru",
+        "sin" => "si",
+        "slk" => "sk",
+        "slv" => "sl",
+        "sna" => "sn",
+        "spa" => "es",
+        "srp" => "sr",
+        "swe" => "sv",
+        "tam" => "ta",
+        "tel" => "te",
+        "tgl" => "tl",
+        "tha" => "th",
+        "tuk" => "tk",
+        "tur" => "tr",
+        "ukr" => "uk",
+        "urd" => "ur",
+        "uzb" => "uz",
+        "vie" => "vi",
+        "yid" => "yi",
+        "zul" => "zu",
+        _ => return None,
+    })
+}
+
+/// Returns sending client from `User-Agent`, or `X-Mailer` when missing.
+pub fn get_user_agent(message: &Message) -> Option<String> {
+    ["User-Agent", "X-Mailer"].iter().find_map(|header| {
+        raw_header(message, header)
+            .map(|value| value.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|value| !value.is_empty())
+    })
+}
+
+/// Returns spam score from `X-Spam-Score` or `score=` of `X-Spam-Status`.
+pub fn get_spam_score(message: &Message) -> Option<String> {
+    let score = match raw_header(message, "X-Spam-Score") {
+        Some(value) => value.split_whitespace().next()?,
+        None => raw_header(message, "X-Spam-Status")?
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .find_map(|field| {
+                field
+                    .strip_prefix("score=")
+                    .or_else(|| field.strip_prefix("hits="))
+            })?,
+    };
+    score.parse::<f64>().ok().map(|_| score.to_owned())
+}
+
+/// Returns urls from text body and `href`/`src` attributes of html body,
+/// without duplicates.
+pub fn get_links(message: &Message) -> Vec<String> {
+    let mut links: Vec<String> = Vec::new();
+    let mut add_link = |link: &str| {
+        if !link.is_empty() && !links.iter().any(|known| known == link) {
+            links.push(link.to_owned());
+        }
+    };
+    if let Some(PartType::Text(text)) = message.text_part(0).map(|part| &part.body) {
+        let mut rest = text.as_ref();
+        while let Some((start, url)) = find_url(rest) {
+            add_link(url);
+            rest = &rest[start + url.len()..];
+        }
+    }
+    if let Some(html) = original_html_body(message) {
+        html_link_attributes(html)
+            .iter()
+            .for_each(|link| add_link(&link.replace("&amp;", "&")));
+    }
+    links
+}
+
+fn html_link_attributes(html: &str) -> Vec<&str> {
+    attribute_ranges(html, &["href", "src"])
+        .into_iter()
+        .map(|(start, end)| html[start..end].trim())
+        .collect()
+}
+
+/// Returns unfolded `Received` headers, most recent hop first.
+pub fn get_received_chain(message: &Message) -> Vec<String> {
+    message
+        .headers()
+        .iter()
+        .filter(|header| header.name == HeaderName::Rfc(RfcHeader::Received))
+        .filter_map(|header| match &header.value {
+            HeaderValue::Text(text) => Some(text.split_whitespace().collect::<Vec<_>>().join(" ")),
+            _ => None,
+        })
+        .collect()
+}
+
+/// How the text body is read, shared by [`HtmlOptions`] and
+/// [`RebuildOptions`].
+#[derive(Default, Clone)]
+pub struct BodyOptions {
+    pub charset_detect: bool,
+    /// Base64 decode text body even when it is not declared as such.
+    pub force_decode: bool,
+    /// Text used instead of the text body of the message.
+    pub text: Option<String>,
+}
+
+/// Returns the first text body of the message, without front-matter, or
+/// [`BodyOptions::text`] when it is set.
+pub fn text_body(message: &Message, options: &BodyOptions) -> String {
+    if let Some(text) = &options.text {
+        return text.clone();
+    }
+    let text = decoded_text_body(message, options);
+    match split_front_matter(&text) {
+        Some((_, body)) => body.to_owned(),
+        None => text,
+    }
+}
+
+fn decoded_text_body(message: &Message, options: &BodyOptions) -> String {
+    if options.force_decode {
+        if let Some(decoded) = force_decoded_text_body(message) {
+            return String::from_utf8(decoded).unwrap();
+        }
+    }
+    // eg. multipart/mixed with attachments only
+    let Some(text) = message.body_text(0) else {
+        return String::new();
+    };
+    if options.charset_detect {
+        if let Some(detected) = detect_charset_text_body(message, &text) {
+            return detected;
+        }
+    }
+    text.to_string()
+}
+
+/// Per message options given in `---` delimited block at the top of text body.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct FrontMatter {
+    pub pixel_url: Option<String>,
+    pub css: Option<String>,
+    pub subject_prefix: Option<String>,
+}
+
+/// Returns front-matter of the text body, empty one when there is none.
+pub fn get_front_matter(message: &Message, options: &BodyOptions) -> FrontMatter {
+    split_front_matter(&decoded_text_body(message, options))
+        .map(|(front_matter, _)| front_matter)
+        .unwrap_or_default()
+}
+
+// only flat `key: value` pairs are supported, unknown keys are ignored
+fn split_front_matter(text: &str) -> Option<(FrontMatter, &str)> {
+    let mut lines = text.split_inclusive('\n');
+    let first_line = lines.next()?;
+    if first_line.trim_end() != "---" {
+        return None;
+    }
+    let mut front_matter = FrontMatter::default();
+    let mut has_known_key = false;
+    let mut offset = first_line.len();
+    for line in lines {
+        offset += line.len();
+        if line.trim_end() == "---" {
+            // `---` can be horizontal rule as well, so plain text stays in the body
+            return has_known_key.then_some((front_matter, &text[offset..]));
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once(':')?;
+        let value = Some(value.trim().trim_matches(['"', '\'']).to_owned());
+        match key.trim() {
+            "pixel_url" => front_matter.pixel_url = value,
+            "css" => front_matter.css = value,
+            "subject_prefix" => front_matter.subject_prefix = value,
+            key if !key.is_empty() && !key.contains(char::is_whitespace) => continue,
+            _ => return None,
+        }
+        has_known_key = true;
+    }
+    None
+}
+
+fn raw_text_body(message: &Message) -> Option<Vec<u8>> {
+    let part = message.text_part(0)?;
+    let raw = message.raw_message.get(part.offset_body..part.offset_end)?;
+    match part.encoding {
+        Encoding::Base64 => base64_decode(raw),
+        Encoding::QuotedPrintable => quoted_printable_decode(raw),
+        Encoding::None => Some(raw.to_vec()),
+    }
+}
+
+// body without transfer encoding that still is a valid base64 utf-8 text
+fn force_decoded_text_body(message: &Message) -> Option<Vec<u8>> {
+    let part = message.text_part(0)?;
+    if part.encoding != Encoding::None {
+        return None;
+    }
+    let raw = message.raw_message.get(part.offset_body..part.offset_end)?;
+    let encoded: Vec<u8> = raw
+        .iter()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .copied()
+        .collect();
+    let looks_base64 = !encoded.is_empty()
+        && encoded.len().is_multiple_of(4)
+        && encoded
+            .iter()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"+/=".contains(byte));
+    if !looks_base64 {
+        return None;
+    }
+    let decoded = base64_decode(&encoded)?;
+    std::str::from_utf8(&decoded).is_ok().then_some(decoded)
+}
+
+fn detect_charset_text_body(message: &Message, decoded: &str) -> Option<String> {
+    let has_charset = message
+        .text_part(0)?
+        .content_type()
+        .and_then(|content_type| content_type.attribute("charset"))
+        .is_some();
+    if has_charset && !decoded.contains(char::REPLACEMENT_CHARACTER) {
+        return None;
+    }
+    let raw = raw_text_body(message)?;
+    let mut detector = chardetng::EncodingDetector::new(chardetng::Iso2022JpDetection::Allow);
+    detector.feed(&raw, true);
+    let (text, _, _) = detector
+        .guess(None, chardetng::Utf8Detection::Allow)
+        .decode(&raw);
+    Some(text.into_owned())
+}
+
+// body_html falls back to converted text, only a real html part counts here
+fn original_html_body<'a>(message: &'a Message) -> Option<&'a str> {
+    match &message.html_part(0)?.body {
+        PartType::Html(html) => Some(html.as_ref()),
+        _ => None,
+    }
+}
+
+fn is_table_row(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with('|') || line.ends_with('|')
+}
+
+/// Removes quoted lines nested deeper than `keep_levels`, together with the
+/// `On ... wrote:` attribution introducing them.
+pub fn strip_quotes(text: &str, keep_levels: usize) -> String {
+    let lines: Vec<(usize, &str, &str)> = text
+        .lines()
+        .map(|line| {
+            let (level, content) = split_quote_prefix(line);
+            (level, content, line)
+        })
+        .collect();
+    let mut result = String::new();
+    let mut index = 0;
+    while index < lines.len() {
+        let (level, content, line) = lines[index];
+        if level > keep_levels {
+            index += 1;
+            continue;
+        }
+        // attribution may be wrapped, eg. when it has a long date and name
+        let attribution_lines = match lines.get(index + 1) {
+            _ if is_attribution(content) => 1,
+            Some((next_level, next, _))
+                if *next_level == level
+                    && content.trim_start().starts_with("On ")
+                    && next.trim_end().ends_with("wrote:") =>
+            {
+                2
+            }
+            _ => 0,
+        };
+        let is_followed_by_quote = lines[index + attribution_lines..]
+            .iter()
+            .find(|(_, next, _)| !next.trim().is_empty())
+            .is_some_and(|(next_level, _, _)| *next_level > level);
+        if level == keep_levels && attribution_lines > 0 && is_followed_by_quote {
+            index += attribution_lines;
+            continue;
+        }
+        result.push_str(line);
+        result.push('\n');
+        index += 1;
+    }
+    format!("{}\n", result.trim_end())
+}
+
+fn split_quote_prefix(line: &str) -> (usize, &str) {
+    let mut depth = 0;
+    let mut rest = line;
+    while let Some(stripped) = rest.strip_prefix('>') {
+        depth += 1;
+        // "> > text" is common in non-flowed replies, treat it as one prefix
+        rest = stripped.strip_prefix(' ').unwrap_or(stripped);
+    }
+    (depth, rest)
+}
+
+fn is_attribution(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with("On ") && line.ends_with("wrote:")
+}
+
+/// Forces markdown line breaks so plain text keeps its layout.
+///
+/// With `break_on` only lines matching it get the break. Lines inside code
+/// fences are matched too, a break there shows up as two trailing spaces.
+pub fn pre_markdown(text: &str, tables: bool, break_on: Option<&Regex>) -> String {
+    let mut result = String::new();
+    text.lines().for_each(|line| {
+        if tables && is_table_row(line) {
+            // forced break would end the table after first row
+            result.push_str(&format!("{line}\n"));
+        } else if break_on.is_some_and(|pattern| !pattern.is_match(line)) {
+            result.push_str(&format!("{line}\n"));
+        } else {
+            // append two spaces to force line break
+            result.push_str(&format!("{line}  \n"));
+        }
+    });
+    result
+}
+
+fn is_code_fence(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("```") || line.starts_with("~~~")
+}
+
+// applies `map` to text outside of fenced and indented code blocks and
+// backtick code spans, markdown shows those verbatim
+fn map_outside_code(text: &str, map: impl Fn(&str) -> String) -> String {
+    let mut result = String::new();
+    let mut in_fence = false;
+    let mut in_indented = false;
+    let mut previous_blank = true;
+    for line in text.split_inclusive('\n') {
+        let is_fence = is_code_fence(line);
+        if is_fence {
+            in_fence = !in_fence;
+        }
+        let is_blank = line.trim().is_empty();
+        if !is_blank {
+            let is_indented = line.starts_with("    ") || line.starts_with('\t');
+            in_indented = !in_fence && is_indented && (in_indented || previous_blank);
+        }
+        if in_fence || is_fence || in_indented {
+            result.push_str(line);
+        } else {
+            result.push_str(&map_outside_code_spans(line, &map));
+        }
+        previous_blank = is_blank;
+    }
+    result
+}
+
+fn map_outside_code_spans(line: &str, map: &impl Fn(&str) -> String) -> String {
+    let mut result = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find('`') {
+        let ticks = backtick_run_len(&rest[start..]);
+        let after = &rest[start + ticks..];
+        // span is closed by backtick run of the same length
+        let Some(end) = find_backtick_run(after, ticks) else {
+            break;
+        };
+        result.push_str(&map(&rest[..start]));
+        result.push_str(&rest[start..start + ticks + end + ticks]);
+        rest = &after[end + ticks..];
+    }
+    result.push_str(&map(rest));
+    result
+}
+
+fn backtick_run_len(text: &str) -> usize {
+    text.len() - text.trim_start_matches('`').len()
+}
+
+fn find_backtick_run(text: &str, len: usize) -> Option<usize> {
+    let mut offset = 0;
+    while let Some(index) = text[offset..].find('`') {
+        let start = offset + index;
+        let run_len = backtick_run_len(&text[start..]);
+        if run_len == len {
+            return Some(start);
+        }
+        offset = start + run_len;
+    }
+    None
+}
+
+fn is_delimiter_row(line: &str) -> bool {
+    let line = line.trim();
+    line.contains('-') && line.chars().all(|ch| matches!(ch, '|' | '-' | ':' | ' '))
+}
+
+fn split_pipe_row(line: &str) -> Option<Vec<&str>> {
+    let line = line.trim();
+    if !line.contains('|') {
+        return None;
+    }
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = line.strip_suffix('|').unwrap_or(line);
+    let cells: Vec<&str> = line.split('|').map(str::trim).collect();
+    (cells.len() > 1).then_some(cells)
+}
+
+// cells are separated by tab or two and more spaces, returned with their offsets
+fn split_aligned_row(line: &str) -> Option<Vec<(usize, &str)>> {
+    // four spaces indent is a code block
+    if line.starts_with("    ") || line.starts_with('\t') {
+        return None;
+    }
+    let mut cells = Vec::new();
+    let mut start = None;
+    let mut spaces = 0;
+    for (index, ch) in line.char_indices() {
+        if ch == ' ' || ch == '\t' {
+            spaces += if ch == '\t' { 2 } else { 1 };
+            if spaces >= 2 {
+                if let Some(cell_start) = start.take() {
+                    cells.push((cell_start, line[cell_start..index].trim_end()));
+                }
+            }
+        } else {
+            if start.is_none() {
+                start = Some(index);
+            }
+            spaces = 0;
+        }
+    }
+    if let Some(cell_start) = start {
+        cells.push((cell_start, &line[cell_start..]));
+    }
+    (cells.len() > 1).then_some(cells)
+}
+
+fn push_table(result: &mut String, rows: &[Vec<&str>]) {
+    let format_row = |cells: &[&str]| {
+        let cells: Vec<String> = cells.iter().map(|cell| cell.replace('|', "\\|")).collect();
+        format!("| {} |\n", cells.join(" | "))
+    };
+    result.push_str(&format_row(&rows[0]));
+    result.push_str(&format_row(&vec!["---"; rows[0].len()]));
+    rows[1..]
+        .iter()
+        .for_each(|row| result.push_str(&format_row(row)));
+}
+
+/// Rewrites pipe separated rows without delimiter row and column aligned
+/// text into GFM tables, first row becomes the header. Code fences and
+/// valid GFM tables are left as they are.
+pub fn detect_tables(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut result = String::new();
+    let mut in_fence = false;
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        if is_code_fence(line) {
+            in_fence = !in_fence;
+        }
+        if in_fence || is_code_fence(line) {
+            result.push_str(&format!("{line}\n"));
+            index += 1;
+            continue;
+        }
+        let pipe_rows: Vec<Vec<&str>> = lines[index..]
+            .iter()
+            .map_while(|line| split_pipe_row(line))
+            .collect();
+        let pipe_rows: Vec<Vec<&str>> = pipe_rows
+            .iter()
+            .take_while(|row| row.len() == pipe_rows[0].len())
+            .cloned()
+            .collect();
+        if pipe_rows.len() > 1 {
+            let end = index + pipe_rows.len();
+            if lines[index..end].iter().any(|line| is_delimiter_row(line)) {
+                lines[index..end]
+                    .iter()
+                    .for_each(|line| result.push_str(&format!("{line}\n")));
+            } else {
+                push_table(&mut result, &pipe_rows);
+            }
+            index = end;
+            continue;
+        }
+        let aligned_rows: Vec<Vec<(usize, &str)>> = lines[index..]
+            .iter()
+            .map_while(|line| split_aligned_row(line))
+            .collect();
+        let offsets =
+            |row: &Vec<(usize, &str)>| row.iter().map(|(offset, _)| *offset).collect::<Vec<_>>();
+        let aligned_rows: Vec<Vec<&str>> = aligned_rows
+            .iter()
+            .take_while(|row| offsets(row) == offsets(&aligned_rows[0]))
+            .map(|row| row.iter().map(|(_, cell)| *cell).collect())
+            .collect();
+        if aligned_rows.len() > 1 {
+            push_table(&mut result, &aligned_rows);
+            index += aligned_rows.len();
+            continue;
+        }
+        result.push_str(&format!("{line}\n"));
+        index += 1;
+    }
+    result
+}
+
+/// Options for html generated by [`text_body_as_html`].
+#[derive(Default)]
+pub struct HtmlOptions {
+    pub inline_css: bool,
+    pub tables: bool,
+    /// Text body source, [`BodyOptions::text`] is markdown here.
+    pub body: BodyOptions,
+    /// Remove quoted reply nested deeper than this many levels, see
+    /// [`strip_quotes`].
+    pub strip_quotes: Option<usize>,
+    /// Truncate text body to this many bytes before conversion.
+    pub max_body_bytes: Option<usize>,
+    /// Replace `cid:` image sources with `data:` uris of the matching parts.
+    pub inline_cid: bool,
+    /// Route remote image sources through this proxy, see
+    /// [`proxy_image_sources`].
+    pub proxy_images: Option<String>,
+    /// Skip [`pre_markdown`], so line breaks, lists and tables follow
+    /// standard markdown rules.
+    pub no_pre_markdown: bool,
+    /// Levels to shift headings down by, `<h6>` is the lowest.
+    pub heading_offset: u8,
+    /// Replace known `:shortcode:` with unicode emoji.
+    pub emoji: bool,
+    /// Convert columnar text to tables with [`detect_tables`], it also
+    /// enables tables extension.
+    pub detect_tables: bool,
+    /// Only lines matching it get forced break in [`pre_markdown`].
+    pub break_on: Option<Regex>,
+    /// Markdown extensions to enable.
+    pub md_flags: Vec<MarkdownExtension>,
+    /// Wrap bare urls in `<>`, so they become links without autolink.
+    pub linkify: bool,
+    /// Collapse whitespace between tags, `<pre>` and `<code>` are kept as is.
+    pub minify: bool,
+    /// Remove `<!-- -->` comments, eg. raw html placeholders.
+    pub strip_comments: bool,
+    /// Limit of [`text_body_as_html`] output in bytes, it is truncated with
+    /// a notice, or kept for [`check_html_size`] to reject with
+    /// `abort_oversized`.
+    pub max_size: Option<usize>,
+    pub abort_oversized: bool,
+}
+
+fn is_preformatted_tag(tag: &str) -> bool {
+    let name: String = tag
+        .trim_start_matches(['<', '/'])
+        .chars()
+        .take_while(char::is_ascii_alphanumeric)
+        .collect();
+    name.eq_ignore_ascii_case("pre") || name.eq_ignore_ascii_case("code")
+}
+
+fn minify_html(html: &str) -> String {
+    let mut result = String::new();
+    let mut preformatted_depth: usize = 0;
+    let mut whitespace = String::new();
+    let mut chars = html.trim().chars();
+    while let Some(ch) = chars.next() {
+        if preformatted_depth == 0 && ch.is_whitespace() {
+            whitespace.push(ch);
+            continue;
+        }
+        if !whitespace.is_empty() {
+            // whitespace around tags on separate lines is template indentation
+            let is_between_tags = result.ends_with('>') || ch == '<';
+            if !(whitespace.contains('\n') && is_between_tags) {
+                result.push(' ');
+            }
+            whitespace.clear();
+        }
+        if ch != '<' {
+            result.push(ch);
+            continue;
+        }
+        let mut tag = String::from(ch);
+        for ch in chars.by_ref() {
+            tag.push(ch);
+            if ch == '>' {
+                break;
+            }
+        }
+        if is_preformatted_tag(&tag) {
+            if tag.starts_with("</") {
+                preformatted_depth = preformatted_depth.saturating_sub(1);
+            } else if !tag.ends_with("/>") {
+                preformatted_depth += 1;
+            }
+        }
+        result.push_str(&tag);
+    }
+    result
+}
+
+fn linkify(text: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some((start, url)) = find_url(rest) {
+        let before = &rest[..start];
+        result.push_str(before);
+        // already a link in markdown or autolink syntax
+        if before.ends_with('<') || before.ends_with("](") {
+            result.push_str(url);
+        } else {
+            result.push_str(&format!("<{}>", url));
+        }
+        rest = &rest[start + url.len()..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn find_url(text: &str) -> Option<(usize, &str)> {
+    let start = find_url_start(text)?;
+    let url_start = &text[start..];
+    let end = url_start
+        .find(|ch: char| ch.is_whitespace() || ch == '<' || ch == '>')
+        .unwrap_or(url_start.len());
+    Some((start, trim_url_punctuation(&url_start[..end])))
+}
+
+fn find_url_start(text: &str) -> Option<usize> {
+    text.match_indices("http")
+        .map(|(index, _)| index)
+        .find(|&index| {
+            text[index..].starts_with("http://") || text[index..].starts_with("https://")
+        })
+}
+
+// sentence punctuation after url is not part of it, unless parens are balanced
+fn trim_url_punctuation(url: &str) -> &str {
+    let mut url = url;
+    loop {
+        let trimmed = url.trim_end_matches(['.', ',', ';', ':', '!', '?', '\'', '"']);
+        let trimmed = match trimmed.strip_suffix(')') {
+            Some(stripped) if trimmed.matches('(').count() < trimmed.matches(')').count() => {
+                stripped
+            }
+            _ => trimmed,
+        };
+        if trimmed == url {
+            return url;
+        }
+        url = trimmed;
+    }
+}
+
+/// Markdown extension enabled by [`HtmlOptions::md_flags`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MarkdownExtension {
+    Tables,
+    Strikethrough,
+    Footnotes,
+    Superscript,
+    Autolink,
+    Tasklist,
+    Tagfilter,
+    DescriptionLists,
+}
+
+/// Markdown extensions by their `--md-flags` names.
+pub const MD_FLAGS: [(&str, MarkdownExtension); 8] = [
+    ("tables", MarkdownExtension::Tables),
+    ("strikethrough", MarkdownExtension::Strikethrough),
+    ("footnotes", MarkdownExtension::Footnotes),
+    ("superscript", MarkdownExtension::Superscript),
+    ("autolink", MarkdownExtension::Autolink),
+    ("tasklist", MarkdownExtension::Tasklist),
+    ("tagfilter", MarkdownExtension::Tagfilter),
+    ("description-lists", MarkdownExtension::DescriptionLists),
+];
+
+impl MarkdownExtension {
+    /// Returns extension by its name from [`MD_FLAGS`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        MD_FLAGS
+            .iter()
+            .find(|(flag, _)| *flag == name)
+            .map(|(_, extension)| *extension)
+    }
+}
+
+fn get_comrak_options(options: &HtmlOptions) -> ComrakOptions {
+    let mut comrak_options = ComrakOptions::default();
+    comrak_options.extension.table = options.tables || options.detect_tables;
+    for flag in &options.md_flags {
+        let extension = &mut comrak_options.extension;
+        match flag {
+            MarkdownExtension::Tables => extension.table = true,
+            MarkdownExtension::Strikethrough => extension.strikethrough = true,
+            MarkdownExtension::Footnotes => extension.footnotes = true,
+            MarkdownExtension::Superscript => extension.superscript = true,
+            MarkdownExtension::Autolink => extension.autolink = true,
+            MarkdownExtension::Tasklist => extension.tasklist = true,
+            MarkdownExtension::Tagfilter => extension.tagfilter = true,
+            MarkdownExtension::DescriptionLists => extension.description_lists = true,
+        }
+    }
+    comrak_options
+}
+
+/// Converts the markdown text body into html, without document wrapper.
+pub fn text_body_as_html_fragment(message: &Message, options: &HtmlOptions) -> String {
+    let comrak_options = get_comrak_options(options);
+    let tables = comrak_options.extension.table;
+    let mut text = text_body(message, &options.body);
+    if let Some(max_bytes) = options.max_body_bytes {
+        text = truncate_text(&text, max_bytes);
+    }
+    if let Some(keep_levels) = options.strip_quotes {
+        text = strip_quotes(&text, keep_levels);
+    }
+    if options.emoji {
+        text = map_outside_code(&text, emoji::replace_shortcodes);
+    }
+    if options.detect_tables {
+        text = detect_tables(&text);
+    }
+    if options.linkify {
+        text = map_outside_code(&text, linkify);
+    }
+    let markdown = if options.no_pre_markdown {
+        text
+    } else {
+        pre_markdown(&text, tables, options.break_on.as_ref())
+    };
+    let mut html = if options.heading_offset == 0 {
+        markdown_to_html(&markdown, &comrak_options)
+    } else {
+        markdown_to_html_with_heading_offset(&markdown, &comrak_options, options.heading_offset)
+    };
+    if options.inline_cid {
+        html = inline_cid_images(&html, message);
+    }
+    if let Some(proxy) = &options.proxy_images {
+        html = proxy_image_sources(&html, proxy);
+    }
+    if options.strip_comments {
+        strip_html_comments(&html).0
+    } else {
+        html
+    }
+}
+
+fn markdown_to_html_with_heading_offset(
+    markdown: &str,
+    comrak_options: &ComrakOptions,
+    offset: u8,
+) -> String {
+    let arena = comrak::Arena::new();
+    let root = comrak::parse_document(&arena, markdown, comrak_options);
+    for node in root.descendants() {
+        if let comrak::nodes::NodeValue::Heading(heading) = &mut node.data.borrow_mut().value {
+            heading.level = heading.level.saturating_add(offset).min(6);
+        }
+    }
+    let mut html = vec![];
+    comrak::format_html(root, comrak_options, &mut html).unwrap();
+    String::from_utf8(html).unwrap()
+}
+
+fn inline_cid_images(html: &str, message: &Message) -> String {
+    let mut result = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find("cid:") {
+        let (before, cid_start) = rest.split_at(start);
+        result.push_str(before);
+        let quote = before.chars().last().filter(|ch| *ch == '"' || *ch == '\'');
+        let is_src = quote.is_some()
+            && before[..before.len() - 1]
+                .trim_end()
+                .strip_suffix('=')
+                .is_some_and(|attribute| {
+                    attribute.trim_end().to_ascii_lowercase().ends_with(" src")
+                });
+        let end = cid_start
+            .find(|ch: char| Some(ch) == quote || ch.is_whitespace() || ch == '>')
+            .unwrap_or(cid_start.len());
+        let cid = &cid_start[4..end];
+        let part = message
+            .parts
+            .iter()
+            .find(|part| is_src && part.content_id() == Some(cid));
+        match part {
+            Some(part) => result.push_str(&format!(
+                "data:{};base64,{}",
+                get_content_type(part),
+                general_purpose::STANDARD.encode(part.contents())
+            )),
+            None => result.push_str(&cid_start[..end]),
+        }
+        rest = &cid_start[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn truncate_text(text: &str, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text.to_owned();
+    }
+    let end = floor_char_boundary(text, max_bytes);
+    format!("{}\n\n[truncated]\n", &text[..end])
+}
+
+// largest index not above `max` that does not split a character
+fn floor_char_boundary(text: &str, max: usize) -> usize {
+    (0..=max.min(text.len()))
+        .rev()
+        .find(|&index| text.is_char_boundary(index))
+        .unwrap_or(0)
+}
+
+/// Rewrites remote `<img>` sources to `{proxy}/img?url={base64 of source}`,
+/// so image hosts do not see the reader. `cid:` and `data:` ones are kept.
+pub fn proxy_image_sources(html: &str, proxy: &str) -> String {
+    map_image_tags(html, |tag| proxy_image_tag(tag, proxy))
+}
+
+fn proxy_image_tag<'a>(tag: &'a str, proxy: &str) -> Option<Cow<'a, str>> {
+    let (start, end) = remote_source_range(tag)?;
+    let source = tag[start..end].replace("&amp;", "&");
+    Some(
+        format!(
+            "{}{}/img?url={}{}",
+            &tag[..start],
+            proxy.trim_end_matches('/'),
+            general_purpose::URL_SAFE_NO_PAD.encode(source),
+            &tag[end..]
+        )
+        .into(),
+    )
+}
+
+// replaces whole `<img ...>` tags for which `map` returns new content
+fn map_image_tags<'a>(
+    html: &'a str,
+    mut map: impl FnMut(&'a str) -> Option<Cow<'a, str>>,
+) -> String {
+    let lowercase = html.to_ascii_lowercase();
+    let mut result = String::new();
+    let mut offset = 0;
+    while let Some(index) = lowercase[offset..].find("<img") {
+        let tag_start = offset + index;
+        let tag_end = lowercase[tag_start..]
+            .find('>')
+            .map_or(html.len(), |end| tag_start + end + 1);
+        result.push_str(&html[offset..tag_start]);
+        let tag = &html[tag_start..tag_end];
+        result.push_str(&map(tag).unwrap_or(Cow::Borrowed(tag)));
+        offset = tag_end;
+    }
+    result.push_str(&html[offset..]);
+    result
+}
+
+fn remote_source_range(tag: &str) -> Option<(usize, usize)> {
+    let (start, end) = attribute_range(tag, "src")?;
+    let source = tag[start..end].to_ascii_lowercase();
+    (source.starts_with("http://") || source.starts_with("https://")).then_some((start, end))
+}
+
+// byte range of attribute value within tag
+fn attribute_range(tag: &str, name: &str) -> Option<(usize, usize)> {
+    attribute_ranges(tag, &[name]).first().copied()
+}
+
+// byte ranges of values of any of `names` attributes, in order
+fn attribute_ranges(html: &str, names: &[&str]) -> Vec<(usize, usize)> {
+    // ascii lowercase keeps byte offsets of the original
+    let lowercase = html.to_ascii_lowercase();
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while let Some((start, name)) = names
+        .iter()
+        .filter_map(|name| Some((offset + lowercase[offset..].find(name)?, name)))
+        .min()
+    {
+        offset = start + name.len();
+        if !html[..start].ends_with(|ch: char| ch.is_ascii_whitespace()) {
+            continue;
+        }
+        let Some(value) = html[offset..].trim_start().strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let value_start = html.len() - value.len();
+        let range = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let end = value[1..].find(quote).map_or(value.len(), |end| end + 1);
+                (value_start + 1, value_start + end)
+            }
+            _ => {
+                let end = value
+                    .find(|ch: char| ch.is_ascii_whitespace() || ch == '>')
+                    .unwrap_or(value.len());
+                (value_start, value_start + end)
+            }
+        };
+        offset = range.1;
+        ranges.push(range);
+    }
+    ranges
+}
+
+// images with at most 1px width or height only report that email was opened
+fn is_tracking_pixel(tag: &str) -> bool {
+    let is_tiny = |value: &str| {
+        value
+            .trim()
+            .trim_end_matches("px")
+            .trim()
+            .parse::<f64>()
+            .is_ok_and(|size| size <= 1.0)
+    };
+    let attribute = |name: &str| attribute_range(tag, name).map(|(start, end)| &tag[start..end]);
+    let is_tiny_attribute = ["width", "height"]
+        .iter()
+        .any(|name| attribute(name).is_some_and(is_tiny));
+    let is_tiny_style = attribute("style").is_some_and(|style| {
+        style.split(';').any(|declaration| {
+            declaration
+                .split_once(':')
+                .is_some_and(|(property, value)| {
+                    let property = property.trim();
+                    (property.eq_ignore_ascii_case("width")
+                        || property.eq_ignore_ascii_case("height"))
+                        && is_tiny(value)
+                })
+        })
+    });
+    is_tiny_attribute || is_tiny_style
+}
+
+/// Counts of what [`clean_html`] removed or changed.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct CleanupSummary {
+    pub pixels: usize,
+    pub remote_images: usize,
+    pub proxied_images: usize,
+    pub comments: usize,
+}
+
+/// Applies comment stripping, tracking pixel stripping and remote image
+/// proxying or removal of rebuild options to html, counting what changed.
+pub fn clean_html(html: &str, options: &RebuildOptions, summary: &mut CleanupSummary) -> String {
+    let mut html = Cow::Borrowed(html);
+    if options.strip_html_comments {
+        let (stripped, comments) = strip_html_comments(&html);
+        summary.comments += comments;
+        html = stripped.into();
+    }
+    if options.strip_pixels {
+        html = map_image_tags(&html, |tag| {
+            is_tracking_pixel(tag).then(|| {
+                summary.pixels += 1;
+                Cow::Borrowed("")
+            })
+        })
+        .into();
+    }
+    if let Some(proxy) = &options.proxy_images {
+        html = map_image_tags(&html, |tag| {
+            let proxied = proxy_image_tag(tag, proxy);
+            summary.proxied_images += usize::from(proxied.is_some());
+            proxied
+        })
+        .into();
+    } else if options.remove_remote_images {
+        html = map_image_tags(&html, |tag| {
+            remote_source_range(tag).map(|_| {
+                summary.remote_images += 1;
+                Cow::Borrowed("")
+            })
+        })
+        .into();
+    }
+    html.into_owned()
+}
+
+/// Summary of [`clean_html`] applied to original html body, which is what
+/// rebuild keeps with `keep_original_html`.
+pub fn get_cleanup_summary(message: &Message, options: &RebuildOptions) -> CleanupSummary {
+    let mut summary = CleanupSummary::default();
+    if let Some(html) = original_html_body(message) {
+        clean_html(html, options, &mut summary);
+    }
+    summary
+}
+
+// unterminated comment hides the rest of the document, so it is dropped too
+fn strip_html_comments(html: &str) -> (String, usize) {
+    let mut result = String::new();
+    let mut comments = 0;
+    let mut rest = html;
+    while let Some(start) = rest.find("<!--") {
+        result.push_str(&rest[..start]);
+        comments += 1;
+        rest = match rest[start + 4..].find("-->") {
+            Some(end) => &rest[start + 4 + end + 3..],
+            None => "",
+        };
+    }
+    result.push_str(rest);
+    (result, comments)
+}
+
+/// Converts the markdown text body into full html document.
+///
+/// `append` is inserted at the end of the body, eg. tracking pixel from
+/// [`get_pixel_element`].
+///
+/// ```
+/// use enrichmail::{get_pixel_element, text_body_as_html, HtmlOptions};
+/// use mail_parser::Message;
+///
+/// let raw = b"Message-ID: <id@example.com>\r\n\r\n> quoted\r\n";
+/// let message = Message::parse(raw).unwrap();
+/// let pixel = get_pixel_element(&["https://pixel.example.com"], "id@example.com");
+/// let html = text_body_as_html(&message, Some(pixel), &HtmlOptions::default()).unwrap();
+///
+/// assert!(html.contains("<blockquote>"));
+/// assert!(html.contains("https://pixel.example.com/image/"));
+/// ```
+pub fn text_body_as_html(
+    message: &Message,
+    append: Option<String>,
+    options: &HtmlOptions,
+) -> Result<String, String> {
+    let body = text_body_as_html_fragment(message, options);
+    let body_append = append.unwrap_or_default();
+    let extra_css = get_front_matter(message, &options.body)
+        .css
+        .unwrap_or_default();
+    let html = format!(
+        r#"
+        <html>
+            <head>
+            <meta http-equiv="Content-Type" content="text/html charset=UTF-8" />
+            <meta name="generator" content="mutt-html-markdown/0.1" />
+            <style>
+                code {{ margin-left: 20px; background: #ddd; display: inline-block; padding: 10px 16px; font-family: monospace; }}
+                blockquote {{ white-space: normal; border-left: 10px solid #ddd; margin-left: 0; padding-left: 10px }}
+                {}
+            </style>
+            </head>
+            <body>
+                {}
+                {}
+            </body>
+        </html>
+        "#,
+        extra_css, body, body_append
+    );
+    let html = if options.inline_css {
+        // style block is dropped by the inliner, clients tend to strip it anyway
+        css_inline::inline(&html).map_err(|error| format!("Unable to inline css: {}", error))?
+    } else {
+        html
+    };
+    let html = if options.minify {
+        minify_html(&html)
+    } else {
+        html
+    };
+    Ok(match options.max_size {
+        Some(max_size) if html.len() > max_size && !options.abort_oversized => {
+            truncate_html(&html, max_size)
+        }
+        _ => html,
+    })
+}
+
+/// Fails when html is over [`HtmlOptions::max_size`] and oversized output
+/// should be rejected rather than truncated.
+pub fn check_html_size(html: &str, options: &HtmlOptions) -> Result<(), String> {
+    match options.max_size {
+        Some(max_size) if options.abort_oversized && html.len() > max_size => Err(format!(
+            "Generated html has {} bytes, limit is {}",
+            html.len(),
+            max_size
+        )),
+        _ => Ok(()),
+    }
+}
+
+const HTML_TRUNCATED: &str = "<p>[truncated]</p>";
+
+// cut tag is dropped, unclosed ones are left for clients to deal with
+fn truncate_html(html: &str, max_size: usize) -> String {
+    let max_size = max_size.saturating_sub(HTML_TRUNCATED.len());
+    let mut html = &html[..floor_char_boundary(html, max_size)];
+    if html.rfind('<') > html.rfind('>') {
+        html = &html[..html.rfind('<').unwrap()];
+    }
+    format!("{}{}", html, HTML_TRUNCATED)
+}
+
+/// Returns original html body followed by the one generated from markdown,
+/// separated by `<hr>`, to compare both. Without html part only generated
+/// one is returned.
+pub fn debug_html(message: &Message, options: &HtmlOptions) -> Result<String, String> {
+    let generated = text_body_as_html(message, None, options)?;
+    Ok(match original_html_body(message) {
+        Some(original) => format!("{}\n<hr>\n{}", original, generated),
+        None => generated,
+    })
+}
+
+/// Returns message as org-mode entry, with subject headline, From, Date and
+/// Message-ID properties, text body converted from markdown and attachments
+/// listed as `attachment:` links.
+pub fn to_org(message: &Message, options: &BodyOptions) -> String {
+    let from = header_addresses(message.from())
+        .into_iter()
+        .filter_map(format_address)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let properties = [
+        ("FROM", Some(from).filter(|from| !from.is_empty())),
+        ("DATE", message.date().map(|date| date.to_rfc3339())),
+        (
+            "MESSAGE_ID",
+            message.message_id().map(|id| format!("<{}>", id)),
+        ),
+    ];
+    let mut entry = format!(
+        "* {}\n:PROPERTIES:\n",
+        message.subject().unwrap_or("(no subject)")
+    );
+    properties.iter().for_each(|(name, value)| {
+        if let Some(value) = value {
+            entry.push_str(&format!(":{}: {}\n", name, value));
+        }
+    });
+    entry.push_str(":END:\n");
+    entry.push_str(&org::markdown_to_org(&text_body(message, options), 1));
+    if message.attachment_count() > 0 {
+        entry.push_str("** Attachments\n");
+        message
+            .attachments()
+            .enumerate()
+            .for_each(|(index, attachment)| {
+                entry.push_str(&format!(
+                    "- [[attachment:{}]]\n",
+                    get_attachment_name(attachment, index)
+                ));
+            });
+    }
+    entry
+}
+
+const FLOWED_LINE_LENGTH: usize = 72;
+
+fn to_format_flowed(text: &str) -> String {
+    let mut result = String::new();
+    text.lines().for_each(|line| {
+        // signature separator has to keep its trailing space
+        if line == "-- " {
+            result.push_str("-- \n");
+            return;
+        }
+        let (depth, content) = split_quote_prefix(line);
+        let content = content.trim_end();
+        let mut prefix = ">".repeat(depth);
+        if depth > 0 || content.starts_with([' ', '>']) || content.starts_with("From ") {
+            // space stuffing, removed again by flowed aware clients
+            prefix.push(' ');
+        }
+        let width = FLOWED_LINE_LENGTH.saturating_sub(prefix.len());
+        let mut current = String::new();
+        content.split(' ').for_each(|word| {
+            if !current.is_empty() && current.len() + word.len() > width {
+                // trailing space marks soft line break
+                result.push_str(&format!("{prefix}{current}\n"));
+                current.clear();
+            }
+            current.push_str(word);
+            current.push(' ');
+        });
+        result.push_str(&format!("{prefix}{}\n", current.trim_end()));
+    });
+    result
+}
+
+fn flowed_text_part<'a>(text: &str) -> MimePart<'a> {
+    MimePart::new(
+        b_headers::content_type::ContentType::new("text/plain")
+            .attribute("charset", "utf-8")
+            .attribute("format", "flowed"),
+        BodyPart::Text(to_format_flowed(text).into()),
+    )
+}
+
+const REDACTED: &str = "REDACTED";
+
+fn redact_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((_, domain)) => format!("{}@{}", REDACTED, domain),
+        None => REDACTED.to_owned(),
+    }
+}
+
+// malformed headers can have entries without email, those return None
+fn transform_address<'a>(
+    address: &'a Addr,
+    redact: bool,
+) -> Option<b_headers::address::Address<'a>> {
+    let name = address.name.as_ref().map(AsRef::as_ref);
+    let email = address.address.as_ref()?;
+    if redact {
+        Some(b_headers::address::Address::new_address(
+            name.map(|_| REDACTED),
+            redact_email(email),
+        ))
+    } else {
+        Some(b_headers::address::Address::new_address(
+            name,
+            email.clone(),
+        ))
+    }
+}
+
+/// Formats address groups keeping their names, builder leaves out `;` closing
+/// every group, which also drops groups without members like
+/// `undisclosed-recipients:;`.
+fn format_groups(groups: &[&Group], redact: bool) -> String {
+    let mut output = Vec::new();
+    for (pos, group) in groups.iter().enumerate() {
+        if pos > 0 {
+            output.push(b' ');
+        }
+        match group.name.as_deref() {
+            Some(name) if name.bytes().all(is_phrase_char) => {
+                output.extend_from_slice(name.as_bytes())
+            }
+            Some(name) => {
+                rfc2047_encode(name, &mut output).unwrap();
+            }
+            None => (),
+        }
+        output.push(b':');
+        let members = group.addresses.iter().filter_map(|address| {
+            match transform_address(address, redact)? {
+                b_headers::address::Address::Address(member) => Some(member),
+                _ => None,
+            }
+        });
+        for (pos, member) in members.enumerate() {
+            output.extend_from_slice(if pos > 0 { b", " } else { b" " });
+            if let Some(name) = &member.name {
+                rfc2047_encode(name, &mut output).unwrap();
+                output.push(b' ');
+            }
+            output.extend_from_slice(format!("<{}>", member.email).as_bytes());
+        }
+        output.push(b';');
+    }
+    // encoded words are plain ascii
+    String::from_utf8(output).unwrap()
+}
+
+// characters allowed in unquoted display name
+fn is_phrase_char(ch: u8) -> bool {
+    ch.is_ascii_alphanumeric() || b" !#$%&'*+-/=?^_`{|}~".contains(&ch)
+}
+
+fn is_redacted_header(name: &str, redact: Option<&[String]>) -> bool {
+    redact.is_some_and(|headers| {
+        headers
+            .iter()
+            .any(|header| header.trim().eq_ignore_ascii_case(name))
+    })
+}
+
+// headers allowed only once by RFC 5322
+fn is_singleton_header(name: &HeaderName) -> bool {
+    matches!(
+        name,
+        HeaderName::Rfc(
+            RfcHeader::Date
+                | RfcHeader::From
+                | RfcHeader::Sender
+                | RfcHeader::ReplyTo
+                | RfcHeader::To
+                | RfcHeader::Cc
+                | RfcHeader::Bcc
+                | RfcHeader::MessageId
+                | RfcHeader::InReplyTo
+                | RfcHeader::Subject
+        )
+    )
+}
+
+fn is_dropped_header(name: &HeaderName, options: &RebuildOptions) -> bool {
+    match name {
+        // builder writes its own for the new content
+        HeaderName::Rfc(RfcHeader::ContentTransferEncoding) => true,
+        HeaderName::Rfc(RfcHeader::ReturnPath) => !options.keep_return_path,
+        HeaderName::Rfc(RfcHeader::Sender) => options.drop_sender,
+        _ => false,
+    }
+}
+
+// well-known headers whose canonical form is not plain title case
+const CANONICAL_HEADER_NAMES: [&str; 12] = [
+    "Message-ID",
+    "Resent-Message-ID",
+    "Content-ID",
+    "MIME-Version",
+    "DKIM-Signature",
+    "ARC-Seal",
+    "ARC-Message-Signature",
+    "ARC-Authentication-Results",
+    "List-ID",
+    "X-MS-Has-Attach",
+    "X-MS-TNEF-Correlator",
+    "X-MSMail-Priority",
+];
+
+fn normalize_header_name(name: &str) -> String {
+    if let Some(canonical) = CANONICAL_HEADER_NAMES
+        .iter()
+        .find(|canonical| canonical.eq_ignore_ascii_case(name))
+    {
+        return (*canonical).to_owned();
+    }
+    name.split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => {
+                    first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase()
+                }
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// builder folds message ids between ids and text at whitespace, so lists are
+// passed in a form it can fold within 78 characters per line
+fn fold_text_list<'a>(name: &HeaderName, text_list: &'a [Cow<'a, str>]) -> HeaderType<'a> {
+    if is_message_id_header(name) {
+        HeaderType::MessageId(b_headers::message_id::MessageId::new_list(
+            text_list.iter().map(|id| id.as_ref()),
+        ))
+    } else {
+        HeaderType::Text(b_headers::text::Text::new(text_list.join(", ")))
+    }
+}
+
+// parser strips angle brackets of ids, builder adds them back for these
+fn is_message_id_header(name: &HeaderName) -> bool {
+    matches!(
+        name,
+        HeaderName::Rfc(
+            RfcHeader::MessageId
+                | RfcHeader::References
+                | RfcHeader::InReplyTo
+                | RfcHeader::ResentMessageId,
+        )
+    )
+}
+
+fn rebuilt_subject(source: &Message, prefix: Option<&str>, options: &RebuildOptions) -> String {
+    let mut subject = source.subject().unwrap_or("").to_owned();
+    if let Some(template) = &options.subject_template {
+        subject = template.replace("{subject}", &subject);
+    }
+    if let Some(prefix) = prefix {
+        subject = format!("{} {}", prefix, subject);
+    }
+    subject
+}
+
+fn is_overridden_header(name: &HeaderName, options: &RebuildOptions) -> bool {
+    match name {
+        HeaderName::Rfc(RfcHeader::To) => !options.to_override.is_empty(),
+        HeaderName::Rfc(RfcHeader::Cc) => !options.cc_override.is_empty(),
+        _ => false,
+    }
+}
+
+fn override_address_header<'a>(
+    dest: MessageBuilder<'a>,
+    name: &'static str,
+    addresses: &[String],
+) -> MessageBuilder<'a> {
+    if addresses.is_empty() {
+        return dest;
+    }
+    let new_addresses = addresses
+        .iter()
+        .filter_map(|address| parse_addresses(address))
+        .flatten()
+        .map(|(name, email)| b_headers::address::Address::new_address(name, email))
+        .collect();
+    dest.header(
+        name,
+        HeaderType::Address(b_headers::address::Address::List(new_addresses)),
+    )
+}
+
+/// Parses address argument, eg. `Carol <carol@example.com>` or comma
+/// separated list, into name and email pairs, `None` when it has no email.
+pub fn parse_addresses(value: &str) -> Option<Vec<(Option<String>, String)>> {
+    let header = format!("To: {}\r\n\r\n", value);
+    let message = Message::parse(header.as_bytes())?;
+    let addresses = header_addresses(message.to())
+        .iter()
+        .map(|address| {
+            let email = address.address.as_ref()?;
+            Some((
+                address.name.as_ref().map(|name| name.to_string()),
+                email.to_string(),
+            ))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (!addresses.is_empty()).then_some(addresses)
+}
+
+fn get_self_references(references: Vec<&str>, message_id: &str) -> Vec<String> {
+    let mut references: Vec<String> = references.into_iter().map(str::to_owned).collect();
+    if !references.iter().any(|reference| reference == message_id) {
+        references.push(message_id.to_owned());
+    }
+    references
+}
+
+/// Returns Message-ID written by the builder, including one generated for
+/// message without it, `None` when it is redacted.
+pub fn get_builder_message_id<'a>(eml: &'a MessageBuilder) -> Option<&'a str> {
+    eml.headers.iter().find_map(|(name, value)| match value {
+        HeaderType::MessageId(id) if name.eq_ignore_ascii_case("Message-ID") => {
+            id.id.first().map(AsRef::as_ref)
+        }
+        _ => None,
+    })
+}
+
+// same id for every serialization of the builder, eg. printed and stored copy
+fn generate_message_id(source: &Message) -> String {
+    let domain = source
+        .message_id()
+        .and_then(|id| id.rsplit_once('@'))
+        .map_or("localhost", |(_, domain)| domain);
+    format!("{}@{}", make_boundary("."), domain)
+}
+
+/// Copies headers of `source` into `dest`, except content ones.
+pub fn copy_headers<'a>(
+    mut dest: MessageBuilder<'a>,
+    source: &'a Message,
+    options: &RebuildOptions,
+) -> MessageBuilder<'a> {
+    let redact = options.redact.as_deref();
+    let redact_addresses = redact.is_some();
+    let mut has_references = false;
+    let subject_prefix = get_front_matter(source, &options.body).subject_prefix;
+    let mut seen_singletons: Vec<&HeaderName> = vec![];
+    let mut overridden: Vec<&HeaderName> = vec![];
+    let message_id: Cow<str> = match source.message_id() {
+        Some(id) if !options.new_message_id => id.into(),
+        _ => generate_message_id(source).into(),
+    };
+    let mut has_message_id = false;
+    // trace headers go first, in their original order
+    let is_received = |header: &&Header| header.name == HeaderName::Rfc(RfcHeader::Received);
+    let headers = source.headers().iter().filter(is_received).chain(
+        source
+            .headers()
+            .iter()
+            .filter(|header| !is_received(header)),
+    );
+    for header in headers {
+        let name: Cow<str> = if options.normalize_header_case {
+            normalize_header_name(header.name()).into()
+        } else {
+            header.name().into()
+        };
+        if is_overridden_header(&header.name, options) {
+            // override takes place of the first original header
+            if !overridden.contains(&&header.name) {
+                overridden.push(&header.name);
+                dest = match header.name {
+                    HeaderName::Rfc(RfcHeader::To) => {
+                        override_address_header(dest, "To", &options.to_override)
+                    }
+                    _ => override_address_header(dest, "Cc", &options.cc_override),
+                };
+            }
+            continue;
+        }
+        if is_dropped_header(&header.name, options) {
+            continue;
+        }
+        if options.dedupe_headers && is_singleton_header(&header.name) {
+            if seen_singletons.contains(&&header.name) {
+                continue;
+            }
+            seen_singletons.push(&header.name);
+        }
+        if options.self_reference && header.name == HeaderName::Rfc(RfcHeader::References) {
+            let references = header.value().as_text_list().unwrap_or_default();
+            dest = dest.references(get_self_references(references, &message_id));
+            has_references = true;
+            continue;
+        }
+        if is_redacted_header(header.name(), redact) {
+            has_message_id |= header.name == HeaderName::Rfc(RfcHeader::MessageId);
+            dest = dest.header(name, HeaderType::Text(b_headers::text::Text::new(REDACTED)));
+            continue;
+        }
+        if header.name == HeaderName::Rfc(RfcHeader::MessageId) {
+            // builder generates new one when header is not named exactly Message-ID
+            if !has_message_id {
+                dest = dest.message_id(message_id.to_string());
+                has_message_id = true;
+            }
+            continue;
+        }
+        if header.name == HeaderName::Rfc(RfcHeader::Subject)
+            && (options.subject_template.is_some() || subject_prefix.is_some())
+        {
+            // subject is already decoded here, builder encodes it again if needed
+            dest = dest.subject(rebuilt_subject(source, subject_prefix.as_deref(), options));
+            continue;
+        }
+        let maybe_header = match header.value() {
+            HeaderValue::Address(address) => match transform_address(address, redact_addresses) {
+                Some(new_address) => Some(HeaderType::Address(new_address)),
+                // keep name-only form, eg. `From: "John Doe"`
+                None => address.name.as_ref().map(|name| {
+                    let name = if redact_addresses {
+                        REDACTED
+                    } else {
+                        name.as_ref()
+                    };
+                    HeaderType::Text(b_headers::text::Text::new(name))
+                }),
+            },
+            // Received is kept folded by parser, builder would fold it again
+            HeaderValue::Text(text) if header.name == HeaderName::Rfc(RfcHeader::Received) => {
+                Some(HeaderType::Text(b_headers::text::Text::new(
+                    text.split_whitespace().collect::<Vec<_>>().join(" "),
+                )))
+            }
+            HeaderValue::Text(text) if is_message_id_header(&header.name) => Some(
+                HeaderType::MessageId(b_headers::message_id::MessageId::new(text.as_ref())),
+            ),
+            HeaderValue::Text(text) => {
+                Some(HeaderType::Text(b_headers::text::Text::new(text.as_ref())))
+            }
+            HeaderValue::DateTime(datetime) => Some(HeaderType::Date(b_headers::date::Date::new(
+                datetime.to_timestamp(),
+            ))),
+            // content will be generated automatically, it will mess up email if copied here
+            HeaderValue::ContentType(_) => None,
+            HeaderValue::Group(group) => Some(HeaderType::Raw(b_headers::raw::Raw::new(
+                format_groups(&[group], redact_addresses),
+            ))),
+            HeaderValue::GroupList(groups) => Some(HeaderType::Raw(b_headers::raw::Raw::new(
+                format_groups(&groups.iter().collect::<Vec<_>>(), redact_addresses),
+            ))),
+            HeaderValue::AddressList(_) => {
+                let new_addresses: Vec<_> = header_addresses(header.value())
+                    .into_iter()
+                    .filter_map(|address| transform_address(address, redact_addresses))
+                    .collect();
+                (!new_addresses.is_empty()).then_some(HeaderType::Address(
+                    b_headers::address::Address::List(new_addresses),
+                ))
+            }
+            HeaderValue::TextList(text_list) => Some(fold_text_list(&header.name, text_list)),
+            // nothing to copy, eg. `Cc:` with no value
+            HeaderValue::Empty => None,
+        };
+        if let Some(new_header) = maybe_header {
+            dest = dest.header(name, new_header);
+        };
+    }
+    if !has_message_id {
+        dest = dest.message_id(message_id.to_string());
+    }
+    let has_subject = source
+        .headers()
+        .iter()
+        .any(|header| header.name == HeaderName::Rfc(RfcHeader::Subject));
+    if !has_subject && (options.subject_template.is_some() || subject_prefix.is_some()) {
+        dest = dest.subject(rebuilt_subject(source, subject_prefix.as_deref(), options));
+    }
+    if options.self_reference && !has_references {
+        dest = dest.references(vec![message_id.into_owned()]);
+    }
+    if !overridden.contains(&&HeaderName::Rfc(RfcHeader::To)) {
+        dest = override_address_header(dest, "To", &options.to_override);
+    }
+    if !overridden.contains(&&HeaderName::Rfc(RfcHeader::Cc)) {
+        dest = override_address_header(dest, "Cc", &options.cc_override);
+    }
+    dest
+}
+
+/// Reads email from file or stdin (`-`), decompressing it when needed.
+pub fn get_email_content(file_path: &String) -> Result<Vec<u8>, String> {
+    let mut content = vec![];
+    if file_path == "-" {
+        std::io::stdin()
+            .read_to_end(&mut content)
+            .map_err(|error| format!("Unable to read stdin: {}", error))?;
+    } else {
+        let path = Path::new(file_path);
+        let mut fh = File::open(path).map_err(|error| format!("Unable to open file: {}", error))?;
+        fh.read_to_end(&mut content)
+            .map_err(|error| format!("Unable to read: {}", error))?;
+    }
+    decompress_email_content(content)
+}
+
+/// Converts Outlook `.msg` content into email with its headers, text and html
+/// body and attachments, so it goes through the same processing as `.eml`.
+pub fn msg_to_eml(content: &[u8]) -> Result<Vec<u8>, String> {
+    let outlook = msg_parser::Outlook::from_slice(content)
+        .map_err(|error| format!("Invalid msg file: {}", error))?;
+    let mut eml = MessageBuilder::new().subject(outlook.subject.as_str());
+    if let Some(from) = msg_addresses(std::slice::from_ref(&outlook.sender)).pop() {
+        eml = eml.from(from);
+    }
+    for (header, persons) in [
+        ("To", &outlook.to),
+        ("Cc", &outlook.cc),
+        ("Bcc", &outlook.bcc),
+    ] {
+        let addresses = msg_addresses(persons);
+        if !addresses.is_empty() {
+            eml = eml.header(
+                header,
+                HeaderType::Address(b_headers::address::Address::new_list(addresses)),
+            );
+        }
+    }
+    let message_id = outlook.headers.message_id.trim_matches(['<', '>', ' ']);
+    if !message_id.is_empty() {
+        eml = eml.message_id(message_id);
+    }
+    // unsent messages have no transport headers, only submit time
+    let date = DateTime::parse_rfc822(&outlook.headers.date)
+        .or_else(|| DateTime::parse_rfc3339(&outlook.client_submit_time))
+        .or_else(|| DateTime::parse_rfc3339(&outlook.message_delivery_time));
+    if let Some(date) = date {
+        eml = eml.date(date.to_timestamp());
+    }
+    eml = eml.text_body(outlook.body.as_str());
+    if !outlook.html.is_empty() {
+        eml = eml.html_body(outlook.html.as_str());
+    }
+    let mut embedded: Vec<(Vec<u8>, String)> = vec![];
+    for attachment in &outlook.attachments {
+        if let Some(nested) = attachment.as_message() {
+            let nested = nested.map_err(|error| format!("Invalid msg file: {}", error))?;
+            let name = format!("{}.eml", nested.subject);
+            embedded.push((msg_to_eml(&attachment.payload_bytes)?, name));
+            continue;
+        }
+        let content_type = match attachment.mime_tag.as_str() {
+            "" => "application/octet-stream",
+            mime_tag => mime_tag,
+        };
+        let mut part = MimePart::new_binary(content_type, attachment.payload_bytes.as_slice());
+        // Outlook gives content id to attachments not referenced by html too
+        if !attachment.content_id.is_empty() {
+            part = part.cid(attachment.content_id.as_str());
+        }
+        let file_name = [
+            &attachment.long_file_name,
+            &attachment.file_name,
+            &attachment.display_name,
+        ]
+        .into_iter()
+        .find(|name| !name.is_empty())
+        .map_or("attachment", String::as_str);
+        eml = add_attachment(eml, part, file_name);
+    }
+    for (content, name) in &embedded {
+        eml = add_attachment(
+            eml,
+            MimePart::new_binary("message/rfc822", content.as_slice()),
+            name,
+        );
+    }
+    Ok(eml.write_to_vec().unwrap())
+}
+
+// Exchange senders may only have X.500 address, they are left out
+fn msg_addresses(persons: &[msg_parser::Person]) -> Vec<b_headers::address::Address<'_>> {
+    persons
+        .iter()
+        .filter(|person| person.email.contains('@'))
+        .map(|person| {
+            let name =
+                Some(person.name.as_str()).filter(|name| !name.is_empty() && *name != person.email);
+            b_headers::address::Address::new_address(name, person.email.as_str())
+        })
+        .collect()
+}
+
+/// Reads only header section of email, so big attachments are not loaded.
+pub fn get_email_headers(file_path: &String) -> Result<Vec<u8>, String> {
+    if file_path == "-" {
+        read_email_headers(std::io::stdin().lock())
+    } else {
+        let fh = File::open(Path::new(file_path))
+            .map_err(|error| format!("Unable to open file: {}", error))?;
+        read_email_headers(std::io::BufReader::new(fh))
+    }
+}
+
+fn read_email_headers(mut reader: impl BufRead) -> Result<Vec<u8>, String> {
+    let read_error = |error: std::io::Error| format!("Unable to read: {}", error);
+    let mut content = vec![];
+    if is_compressed(reader.fill_buf().map_err(read_error)?) {
+        // compressed input has to be read whole anyway
+        reader.read_to_end(&mut content).map_err(read_error)?;
+        return decompress_email_content(content);
+    }
+    loop {
+        let mut line = vec![];
+        let read = reader.read_until(b'\n', &mut line).map_err(read_error)?;
+        content.extend_from_slice(&line);
+        if read == 0 || line == b"\n" || line == b"\r\n" {
+            return Ok(content);
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+#[cfg(feature = "xz")]
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+fn is_compressed(content: &[u8]) -> bool {
+    let magic_bytes: &[&[u8]] = &[
+        #[cfg(feature = "zstd")]
+        ZSTD_MAGIC,
+        #[cfg(feature = "xz")]
+        XZ_MAGIC,
+    ];
+    magic_bytes.iter().any(|magic| content.starts_with(magic))
+}
+
+// dispatch on magic bytes rather than extension, so compressed stdin works as well
+fn decompress_email_content(content: Vec<u8>) -> Result<Vec<u8>, String> {
+    #[cfg(feature = "zstd")]
+    if content.starts_with(ZSTD_MAGIC) {
+        return zstd::decode_all(content.as_slice())
+            .map_err(|error| format!("Unable to decompress zstd input: {}", error));
+    }
+    #[cfg(feature = "xz")]
+    if content.starts_with(XZ_MAGIC) {
+        let mut decompressed = vec![];
+        xz2::read::XzDecoder::new(content.as_slice())
+            .read_to_end(&mut decompressed)
+            .map_err(|error| format!("Unable to decompress xz input: {}", error))?;
+        return Ok(decompressed);
+    }
+    Ok(content)
+}
+
+fn is_mbox_separator(line: &[u8]) -> bool {
+    line.starts_with(b"From ")
+}
+
+// mboxrd quotes body lines looking like separators with '>'
+fn is_quoted_mbox_separator(line: &[u8]) -> bool {
+    let quotes = line.iter().take_while(|&&ch| ch == b'>').count();
+    quotes > 0 && is_mbox_separator(&line[quotes..])
+}
+
+/// Splits mbox content into `From ` separator lines and messages.
+pub fn split_mbox(content: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut messages: Vec<(String, Vec<u8>)> = vec![];
+    for line in content.split_inclusive(|&ch| ch == b'\n') {
+        if is_mbox_separator(line) {
+            let separator = String::from_utf8_lossy(line).trim_end().to_owned();
+            messages.push((separator, vec![]));
+        } else if let Some((_, message)) = messages.last_mut() {
+            if is_quoted_mbox_separator(line) {
+                message.extend_from_slice(&line[1..]);
+            } else {
+                message.extend_from_slice(line);
+            }
+        }
+    }
+    messages
+}
+
+/// Returns `From ` separator line for the message, using its sender and date.
+pub fn get_mbox_separator(message: &Message) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let sender = header_addresses(message.from())
+        .first()
+        .and_then(|from| from.address.as_deref())
+        .unwrap_or("MAILER-DAEMON");
+    // undated messages get current time, as mbox separator requires one
+    let date = utc_date_or_now(message.date());
+    format!(
+        "From {} {} {} {:>2} {:02}:{:02}:{:02} {}",
+        sender,
+        DAYS[date.day_of_week() as usize],
+        MONTHS[(date.month as usize).clamp(1, 12) - 1],
+        date.day,
+        date.hour,
+        date.minute,
+        date.second,
+        date.year
+    )
+}
+
+/// Quotes lines that would be taken for mbox separators.
+pub fn escape_mbox_content(content: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(content.len());
+    content.split_inclusive(|ch| *ch == b'\n').for_each(|line| {
+        let quotes = line.iter().take_while(|&&ch| ch == b'>').count();
+        if is_mbox_separator(&line[quotes..]) {
+            result.push(b'>');
+        }
+        result.extend_from_slice(line);
+    });
+    result
+}
+
+/// Formats byte count with binary units, eg. `1.2 MiB`.
+pub fn human_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Returns tracking pixel `<img>` for every url, eg. main and fallback one,
+/// identified by `id`, eg. `--pixel-id` or Message-ID of rebuilt message.
+pub fn get_pixel_element(tracking_urls: &[&str], id: &str) -> String {
+    let encoded_id: String = general_purpose::STANDARD_NO_PAD.encode(id);
+    tracking_urls
+        .iter()
+        .map(|tracking_url| {
+            let pixel_url = format!("{}/image/{}.gif", tracking_url, encoded_id);
+            format!(
+                r#"
+        <img src="{}" alt="Open pixel" style="border: 0px; width: 0px; max-width: 1px;" />
+        "#,
+                pixel_url
+            )
+        })
+        .collect()
+}
+
+/// Builds pixel base url from scheme and host headers of the message.
+///
+/// Scheme defaults to `https` when its header is missing.
+pub fn get_pixel_base_url(
+    message: &Message,
+    host_header: &str,
+    proto_header: &str,
+) -> Option<String> {
+    let host = raw_header(message, host_header)?.trim();
+    let proto = raw_header(message, proto_header).map_or("https", str::trim);
+    Some(format!("{}://{}", proto, host.trim_end_matches('/')))
+}
+
+/// IMAP authentication mechanism.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImapAuth {
+    #[default]
+    Login,
+    Plain,
+    /// password is used as OAuth2 access token
+    XOAuth2,
+}
+
+/// IMAP connection and target mailbox for [`put_email_on_imap_server`].
+#[derive(Clone)]
+pub struct ImapOptions {
+    /// may contain date placeholders, see [`expand_mailbox_template`]
+    pub mailbox: String,
+    pub server: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+    pub auth: ImapAuth,
+    /// server hierarchy separator, `/` in mailbox is replaced with it
+    pub separator: Option<char>,
+    /// mailbox where copies with the same Message-ID are moved before append
+    pub move_existing: Option<String>,
+}
+
+// SASL mechanisms used here send whole payload as the first response
+struct SaslAuthenticator(String);
+
+impl imap::Authenticator for SaslAuthenticator {
+    type Response = String;
+
+    fn process(&self, _challenge: &[u8]) -> Self::Response {
+        self.0.clone()
+    }
+}
+
+fn utc_date_or_now(date: Option<&DateTime>) -> DateTime {
+    match date {
+        Some(date) => DateTime::from_timestamp(date.to_timestamp()),
+        None => DateTime::from_timestamp(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs() as i64),
+        ),
+    }
+}
+
+/// Expands `{YYYY}`, `{MM}` and `{DD}` in mailbox from UTC date of the
+/// message, current date is used when it has none.
+pub fn expand_mailbox_template(mailbox: &str, message: Option<&Message>) -> String {
+    let date = utc_date_or_now(message.and_then(Message::date));
+    mailbox
+        .replace("{YYYY}", &format!("{:04}", date.year))
+        .replace("{MM}", &format!("{:02}", date.month))
+        .replace("{DD}", &format!("{:02}", date.day))
+}
+
+/// Appends emails to IMAP mailbox using single session, returns result of
+/// every append, or error when session can not be established.
+pub fn put_email_on_imap_server(
+    emails: &[(String, Vec<u8>)],
+    options: &ImapOptions,
+) -> imap::error::Result<Vec<(String, imap::error::Result<()>)>> {
+    let tls = native_tls::TlsConnector::builder()
+        .build()
+        .map_err(imap::error::Error::Tls)?;
+    let client = imap::connect(
+        (options.server.clone(), options.port),
+        &options.server,
+        &tls,
+    )?;
+    let mut imap_session = match options.auth {
+        ImapAuth::Login => client.login(&options.user, &options.password),
+        ImapAuth::Plain => client.authenticate(
+            "PLAIN",
+            &SaslAuthenticator(format!("\0{}\0{}", options.user, options.password)),
+        ),
+        ImapAuth::XOAuth2 => client.authenticate(
+            "XOAUTH2",
+            &SaslAuthenticator(format!(
+                "user={}\x01auth=Bearer {}\x01\x01",
+                options.user, options.password
+            )),
+        ),
+    }
+    .map_err(|(error, _)| error)?;
+
+    let server_mailbox = |mailbox: &str| match options.separator {
+        Some(separator) => mailbox.replace('/', &separator.to_string()),
+        None => mailbox.to_owned(),
+    };
+    let move_existing = options.move_existing.as_deref().map(server_mailbox);
+    Ok(emails
+        .iter()
+        .map(|(name, eml)| {
+            let message = Message::parse(eml);
+            let mailbox =
+                server_mailbox(&expand_mailbox_template(&options.mailbox, message.as_ref()));
+            let result = match &move_existing {
+                Some(destination) => {
+                    move_existing_email(&mut imap_session, &mailbox, destination, eml)
+                }
+                None => Ok(()),
+            }
+            .and_then(|_| {
+                imap_session.append_with_flags(&mailbox, eml, &[imap::types::Flag::Seen])
+            });
+            (name.clone(), result)
+        })
+        .collect())
+}
+
+fn move_existing_email<T: Read + Write>(
+    imap_session: &mut imap::Session<T>,
+    mailbox: &str,
+    destination: &str,
+    eml: &[u8],
+) -> imap::error::Result<()> {
+    let message_id =
+        match Message::parse(eml).and_then(|message| message.message_id().map(str::to_owned)) {
+            Some(message_id) => message_id,
+            None => return Ok(()),
+        };
+    // mailbox does not exist yet, eg. first append to dated archive
+    if imap_session.select(mailbox).is_err() {
+        return Ok(());
+    }
+    let query = format!(
+        "HEADER Message-ID \"{}\"",
+        message_id.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+    let uids = imap_session.uid_search(query)?;
+    if uids.is_empty() {
+        return Ok(());
+    }
+    let uid_set = uids
+        .iter()
+        .map(|uid| uid.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let capabilities = imap_session.capabilities()?;
+    if capabilities.has_str("MOVE") {
+        return imap_session.uid_mv(&uid_set, destination);
+    }
+    imap_session.uid_copy(&uid_set, destination)?;
+    imap_session.uid_store(&uid_set, "+FLAGS (\\Deleted)")?;
+    // plain EXPUNGE would also remove other messages marked as deleted, so
+    // without UIDPLUS moved copies stay flagged until mailbox is expunged
+    if capabilities.has_str("UIDPLUS") {
+        imap_session.uid_expunge(&uid_set)?;
+    }
+    Ok(())
+}
+
+/// Options for [`get_builder_from_parser`].
+#[derive(Default)]
+pub struct RebuildOptions {
+    pub redact: Option<Vec<String>>,
+    pub to_override: Vec<String>,
+    pub cc_override: Vec<String>,
+    pub keep_original_html: bool,
+    /// Remove `<!-- -->` comments from kept original html body.
+    pub strip_html_comments: bool,
+    /// Let builder generate Message-ID instead of copying the original one.
+    pub new_message_id: bool,
+    /// Remove tracking pixels from kept original html body.
+    pub strip_pixels: bool,
+    /// Route remote image sources of kept html through this proxy.
+    pub proxy_images: Option<String>,
+    /// Remove remote images from kept html, unless they are proxied.
+    pub remove_remote_images: bool,
+    pub format_flowed: bool,
+    pub self_reference: bool,
+    pub body: BodyOptions,
+    pub strip_attachments: bool,
+    pub keep_types: Vec<String>,
+    pub subject_template: Option<String>,
+    pub dedupe_headers: bool,
+    /// Return-Path is set by receiving MTA, so it is dropped unless asked for
+    pub keep_return_path: bool,
+    pub drop_sender: bool,
+    pub attach_original: bool,
+    pub normalize_header_case: bool,
+    /// Transfer encoding of text body, picked by `mail_builder` when not set.
+    pub text_encoding: Option<TextEncoding>,
+    /// Sign written message, see [`write_message`].
+    pub dkim: Option<DkimOptions>,
+}
+
+/// DKIM signing key and identity for [`RebuildOptions::dkim`].
+#[derive(Clone)]
+pub struct DkimOptions {
+    /// RSA private key in PKCS#1 or PKCS#8 PEM.
+    pub key: String,
+    pub selector: String,
+    pub domain: String,
+}
+
+// signed when present, missing ones are signed as empty so they can't be added
+const DKIM_HEADERS: [&str; 10] = [
+    "From",
+    "To",
+    "Cc",
+    "Subject",
+    "Date",
+    "Message-ID",
+    "In-Reply-To",
+    "References",
+    "MIME-Version",
+    "Content-Type",
+];
+
+/// Transfer encoding for [`RebuildOptions::text_encoding`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextEncoding {
+    SevenBit,
+    QuotedPrintable,
+    Base64,
+}
+
+impl TextEncoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TextEncoding::SevenBit => "7bit",
+            TextEncoding::QuotedPrintable => "quoted-printable",
+            TextEncoding::Base64 => "base64",
+        }
+    }
+}
+
+/// Rebuilds parsed message (text body, headers and attachments).
+pub fn get_builder_from_parser<'a>(
+    message: &'a Message,
+    options: &RebuildOptions,
+) -> MessageBuilder<'a> {
+    let text = text_body(message, &options.body);
+    let mut eml = if options.format_flowed {
+        MessageBuilder {
+            text_body: Some(flowed_text_part(&text)),
+            ..MessageBuilder::new()
+        }
+    } else {
+        MessageBuilder::new().text_body(text)
+    };
+    if options.keep_original_html {
+        if let Some(html) = original_html_body(message) {
+            let html = clean_html(html, options, &mut CleanupSummary::default());
+            eml = eml.html_body(html);
+        }
+    }
+    eml = copy_headers(eml, message, options);
+    if !options.strip_attachments {
+        eml = copy_attachments(eml, message, options);
+    }
+    if options.attach_original {
+        // raw input bytes, not re-serialized message
+        eml = eml.binary_attachment(
+            "message/rfc822",
+            "original.eml",
+            message.raw_message.as_ref(),
+        );
+    }
+    eml
+}
+
+/// Writes rebuilt message, with text body in [`RebuildOptions::text_encoding`]
+/// and `DKIM-Signature` of [`RebuildOptions::dkim`].
+///
+/// Fails when text body can not be sent in requested encoding, eg. non-ascii
+/// one as 7bit, or when DKIM key can not be used.
+pub fn write_message(eml: MessageBuilder, options: &RebuildOptions) -> Result<Vec<u8>, String> {
+    let output = fold_long_headers(unencode_attached_messages(eml.write_to_vec().unwrap()));
+    let output = match options.text_encoding {
+        Some(encoding) => reencode_text_part(&output, encoding)?,
+        None => output,
+    };
+    match &options.dkim {
+        Some(dkim) => dkim_sign(output, dkim),
+        None => Ok(output),
+    }
+}
+
+// signature goes on top, after every other change to the message
+fn dkim_sign(output: Vec<u8>, options: &DkimOptions) -> Result<Vec<u8>, String> {
+    use mail_auth::common::crypto::{RsaKey, Sha256};
+    use mail_auth::common::headers::HeaderWriter;
+    use rustls_pki_types::pem::PemObject;
+
+    let key = rustls_pki_types::PrivateKeyDer::from_pem_slice(options.key.as_bytes())
+        .map_err(|error| format!("Invalid DKIM key: {}", error))?;
+    let key = RsaKey::<Sha256>::from_key_der(key)
+        .map_err(|error| format!("Invalid DKIM key: {}", error))?;
+    let signature = mail_auth::dkim::DkimSigner::from_key(key)
+        .domain(options.domain.as_str())
+        .selector(options.selector.as_str())
+        .headers(DKIM_HEADERS)
+        .sign(&output)
+        .map_err(|error| format!("Unable to sign message: {}", error))?;
+    let mut signed = signature.to_header().into_bytes();
+    signed.extend_from_slice(&output);
+    Ok(signed)
+}
+
+// mail_builder folds text only after 76 characters were written, so headers
+// with long words end up past 78, those get unfolded and folded again here
+fn fold_long_headers(output: Vec<u8>) -> Vec<u8> {
+    let headers_end = match output.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(position) => position + 2,
+        None => return output,
+    };
+    let mut result = Vec::with_capacity(output.len());
+    let mut header: Vec<u8> = vec![];
+    for line in output[..headers_end].split_inclusive(|ch| *ch == b'\n') {
+        if !header.is_empty() && !matches!(line.first(), Some(b' ' | b'\t')) {
+            result.extend_from_slice(&fold_header(&header));
+            header.clear();
+        }
+        header.extend_from_slice(line);
+    }
+    result.extend_from_slice(&fold_header(&header));
+    result.extend_from_slice(&output[headers_end..]);
+    result
+}
+
+// folds at whitespace so lines fit 78 characters where words allow it,
+// headers already within the limit are kept untouched
+fn fold_header(header: &[u8]) -> Vec<u8> {
+    let is_long = |line: &[u8]| line.strip_suffix(b"\r\n").unwrap_or(line).len() > 78;
+    if !header.split_inclusive(|ch| *ch == b'\n').any(is_long) {
+        return header.to_vec();
+    }
+    // builder folds with a tab in front of the whitespace it folded at
+    let unfolded: Vec<u8> = header
+        .split(|ch| *ch == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .flat_map(|line| match line {
+            [b'\t', b' ' | b'\t', ..] => &line[1..],
+            _ => line,
+        })
+        .copied()
+        .collect();
+    // fold goes before whitespace that follows a word, so no line is blank
+    let is_fold_point = |line: &[u8], position: usize| {
+        matches!(line[position], b' ' | b'\t') && !matches!(line[position - 1], b' ' | b'\t')
+    };
+    let mut result = Vec::with_capacity(header.len() + 16);
+    let mut rest = &unfolded[..];
+    while rest.len() > 78 {
+        let fold = (1..=78)
+            .rev()
+            .find(|&position| is_fold_point(rest, position))
+            .or_else(|| (79..rest.len()).find(|&position| is_fold_point(rest, position)));
+        let Some(fold) = fold else {
+            break;
+        };
+        result.extend_from_slice(&rest[..fold]);
+        result.extend_from_slice(b"\r\n");
+        rest = &rest[fold..];
+    }
+    result.extend_from_slice(rest);
+    result.extend_from_slice(b"\r\n");
+    result
+}
+
+// rewrites Content-Transfer-Encoding of written part headers
+fn replace_transfer_encoding(headers: &[u8], encoding: &str) -> Vec<u8> {
+    String::from_utf8_lossy(headers)
+        .split_inclusive('\n')
+        .map(|line| match line.split_once(':') {
+            Some((name, _)) if name.eq_ignore_ascii_case("Content-Transfer-Encoding") => {
+                format!("{}: {}\r\n", name, encoding)
+            }
+            _ => line.to_owned(),
+        })
+        .collect::<String>()
+        .into_bytes()
+}
+
+// mail_builder writes every non-text part as base64, which RFC 2046 does not
+// allow for message/rfc822, so those get their raw bytes back
+fn unencode_attached_messages(output: Vec<u8>) -> Vec<u8> {
+    let message = Message::parse(&output).unwrap();
+    let ranges: Vec<_> = message
+        .parts
+        .iter()
+        .filter(|part| {
+            part.encoding == Encoding::Base64
+                && part.content_type().is_some_and(|content_type| {
+                    content_type.c_type.eq_ignore_ascii_case("message")
+                        && content_type
+                            .subtype()
+                            .is_some_and(|subtype| subtype.eq_ignore_ascii_case("rfc822"))
+                })
+        })
+        .map(|part| (part.offset_header, part.offset_body, part.offset_end))
+        .collect();
+    if ranges.is_empty() {
+        return output;
+    }
+    let mut result = Vec::with_capacity(output.len());
+    let mut offset = 0;
+    for (header_start, body_start, body_end) in ranges {
+        let raw = base64_decode(&output[body_start..body_end]).unwrap();
+        let encoding =
+            if raw.contains(&0) || raw.split(|ch| *ch == b'\n').any(|line| line.len() > 998) {
+                "binary"
+            } else if raw.is_ascii() {
+                "7bit"
+            } else {
+                "8bit"
+            };
+        result.extend_from_slice(&output[offset..header_start]);
+        result.extend_from_slice(&replace_transfer_encoding(
+            &output[header_start..body_start],
+            encoding,
+        ));
+        result.extend_from_slice(&raw);
+        offset = body_end;
+    }
+    result.extend_from_slice(&output[offset..]);
+    result
+}
+
+// mail_builder has no way to choose transfer encoding, so written text part
+// gets its body and Content-Transfer-Encoding replaced afterwards
+fn reencode_text_part(output: &[u8], encoding: TextEncoding) -> Result<Vec<u8>, String> {
+    let message = Message::parse(output).unwrap();
+    let part = match message.text_part(0) {
+        Some(part) if matches!(part.body, PartType::Text(_)) => part,
+        _ => return Ok(output.to_vec()),
+    };
+    let text = part.text_contents().unwrap().as_bytes();
+    let mut body = Vec::new();
+    match encoding {
+        TextEncoding::SevenBit => {
+            if !text.is_ascii() || text.split(|ch| *ch == b'\n').any(|line| line.len() > 998) {
+                return Err("Text body can not be sent as 7bit".to_owned());
+            }
+            let mut prev_ch = 0;
+            for &ch in text {
+                if ch == b'\n' && prev_ch != b'\r' {
+                    body.push(b'\r');
+                }
+                body.push(ch);
+                prev_ch = ch;
+            }
+        }
+        TextEncoding::QuotedPrintable => {
+            quoted_printable_encode(text, &mut body, false, true).unwrap();
+        }
+        TextEncoding::Base64 => {
+            base64_encode_mime(text, &mut body, false).unwrap();
+        }
+    }
+    let mut result = output[..part.offset_header].to_vec();
+    result.extend_from_slice(&replace_transfer_encoding(
+        &output[part.offset_header..part.offset_body],
+        encoding.as_str(),
+    ));
+    result.extend_from_slice(&body);
+    result.extend_from_slice(&output[part.offset_end..]);
+    Ok(result)
+}
+
+/// Returns attachment file name from `Content-Disposition`.
+pub fn get_file_name(attachment: &MessagePart) -> String {
+    let mut result = String::new();
+    attachment.headers().iter().for_each(|header| {
+        if header.name == HeaderName::Rfc(RfcHeader::ContentDisposition) {
+            if let HeaderValue::ContentType(content_type) = &header.value {
+                // inline parts usually come without file name
+                if let Some(filename) = content_type.attribute("filename") {
+                    result = filename.to_owned();
+                }
+            };
+        }
+    });
+    result
+}
+
+/// Returns attachment file name, from `Content-Type` name when there is no
+/// `Content-Disposition` one, or `attachment-N` built from its position.
+pub fn get_attachment_name(attachment: &MessagePart, index: usize) -> String {
+    attachment
+        .attachment_name()
+        .map_or_else(|| format!("attachment-{}", index + 1), str::to_owned)
+}
+
+/// Returns attachment content type as `type/subtype`.
+pub fn get_content_type(attachment: &MessagePart) -> String {
+    let mut result = String::new();
+    attachment.headers().iter().for_each(|header| {
+        if header.name == HeaderName::Rfc(RfcHeader::ContentType) {
+            if let HeaderValue::ContentType(content_type) = &header.value {
+                result.push_str(content_type.ctype().to_owned().as_str());
+                if let Some(subtype) = content_type.subtype() {
+                    result.push('/');
+                    result.push_str(subtype);
+                }
+            };
+        }
+    });
+    result
+}
+
+/// Returns top-level content type of the message, `text/plain` when missing.
+pub fn get_message_content_type(message: &Message) -> String {
+    let content_type = get_content_type(message.root_part());
+    if content_type.is_empty() {
+        String::from("text/plain")
+    } else {
+        content_type
+    }
+}
+
+/// Returns `Final-Recipient` address and `Status` of every recipient in
+/// delivery status notification, empty for other messages.
+pub fn get_bounce_info(message: &Message) -> Vec<(String, String)> {
+    let is_dsn = message
+        .root_part()
+        .content_type()
+        .is_some_and(|content_type| {
+            content_type.c_type.eq_ignore_ascii_case("multipart")
+                && content_type
+                    .c_subtype
+                    .as_deref()
+                    .is_some_and(|subtype| subtype.eq_ignore_ascii_case("report"))
+                && content_type
+                    .attribute("report-type")
+                    .is_some_and(|report_type| report_type.eq_ignore_ascii_case("delivery-status"))
+        });
+    if !is_dsn {
+        return vec![];
+    }
+    let status_part = message
+        .parts
+        .iter()
+        .find(|part| get_content_type(part).eq_ignore_ascii_case("message/delivery-status"));
+    let report = match status_part {
+        Some(part) => String::from_utf8_lossy(part.contents()).replace("\r\n", "\n"),
+        None => return vec![],
+    };
+    // first block has per-message fields, every next one is a recipient
+    report
+        .split("\n\n")
+        .filter_map(|block| {
+            let field = |name: &str| {
+                block.lines().find_map(|line| {
+                    let (field_name, value) = line.split_once(':')?;
+                    field_name
+                        .trim()
+                        .eq_ignore_ascii_case(name)
+                        .then(|| value.trim())
+                })
+            };
+            let recipient = field("Final-Recipient")?;
+            // address type comes first, eg. `rfc822; john@example.com`
+            let recipient = recipient
+                .split_once(';')
+                .map_or(recipient, |(_, address)| address);
+            Some((recipient.trim().to_owned(), field("Status")?.to_owned()))
+        })
+        .collect()
+}
+
+/// Returns MIME structure, a line per part indented by its depth, with
+/// content type, disposition and size. Multipart size is of its raw body.
+pub fn get_mime_tree(message: &Message) -> Vec<String> {
+    let mut lines = Vec::new();
+    push_mime_tree(message, 0, 0, &mut lines);
+    lines
+}
+
+fn push_mime_tree(message: &Message, part_id: usize, depth: usize, lines: &mut Vec<String>) {
+    let part = match message.parts.get(part_id) {
+        Some(part) => part,
+        None => return,
+    };
+    let mut content_type = get_content_type(part);
+    if content_type.is_empty() {
+        content_type = String::from("text/plain");
+    }
+    let disposition = part
+        .content_disposition()
+        .map(|disposition| format!(" {}", disposition.c_type))
+        .unwrap_or_default();
+    let size = match part.body {
+        PartType::Multipart(_) => part.offset_end.saturating_sub(part.offset_body),
+        _ => part.len(),
+    };
+    lines.push(format!(
+        "{}{}{} ({} bytes)",
+        "  ".repeat(depth),
+        content_type,
+        disposition,
+        size
+    ));
+    match &part.body {
+        PartType::Multipart(children) => children
+            .iter()
+            .for_each(|child| push_mime_tree(message, *child, depth + 1, lines)),
+        PartType::Message(nested) => push_mime_tree(nested, 0, depth + 1, lines),
+        _ => (),
+    }
+}
+
+/// Checks if message is signed or encrypted, rebuilding would break those.
+pub fn is_signed_or_encrypted(message: &Message) -> bool {
+    matches!(
+        get_message_content_type(message)
+            .to_ascii_lowercase()
+            .as_str(),
+        "multipart/signed"
+            | "multipart/encrypted"
+            | "application/pkcs7-mime"
+            | "application/x-pkcs7-mime"
+    )
+}
+
+/// Returns attachments which could not be decoded.
+pub fn get_corrupted_attachments<'a>(message: &'a Message) -> Vec<&'a MessagePart<'a>> {
+    message
+        .attachments()
+        .filter(|attachment| attachment.is_encoding_problem)
+        .collect()
+}
+
+fn is_kept_type(content_type: &str, keep_types: &[String]) -> bool {
+    keep_types.is_empty()
+        || keep_types.iter().any(|keep_type| {
+            match keep_type.strip_suffix("/*") {
+                // wildcard subtype, e.g. image/*
+                Some(ctype) => content_type
+                    .split_once('/')
+                    .map_or(content_type, |(ctype, _)| ctype)
+                    .eq_ignore_ascii_case(ctype),
+                None => content_type.eq_ignore_ascii_case(keep_type),
+            }
+        })
+}
+
+/// Returns attachment counts per content type, most common first.
+pub fn get_attachment_type_summary(message: &Message) -> Vec<(String, usize)> {
+    let mut summary: Vec<(String, usize)> = vec![];
+    for attachment in message.attachments() {
+        let content_type = get_content_type(attachment);
+        match summary.iter_mut().find(|(ctype, _)| *ctype == content_type) {
+            Some((_, count)) => *count += 1,
+            None => summary.push((content_type, 1)),
+        }
+    }
+    summary.sort_by(|(a_type, a_count), (b_type, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_type.cmp(b_type))
+    });
+    summary
+}
+
+/// Digest algorithm for [`get_attachment_hash`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HashAlgorithm {
+    Sha256,
+    Md5,
+}
+
+/// Returns hex digest of decoded attachment content.
+pub fn get_attachment_hash(attachment: &MessagePart, algorithm: HashAlgorithm) -> String {
+    use sha2::Digest;
+    let contents = attachment.contents();
+    let digest = match algorithm {
+        HashAlgorithm::Sha256 => sha2::Sha256::digest(contents).to_vec(),
+        HashAlgorithm::Md5 => md5::Md5::digest(contents).to_vec(),
+    };
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Copies attachments of `source` into `dest`.
+pub fn copy_attachments<'a>(
+    mut dest: MessageBuilder<'a>,
+    source: &'a Message,
+    options: &RebuildOptions,
+) -> MessageBuilder<'a> {
+    for attachment in source
+        .attachments()
+        .filter(|attachment| is_copied_attachment(attachment, options))
+    {
+        let content_type = get_content_type(attachment);
+        let file_name = get_file_name(attachment);
+
+        if options.redact.is_some() {
+            // keep the part with its content type, but without any content
+            let part = MimePart::new_binary(content_type, &[][..]);
+            dest = add_attachment(dest, part, &file_name);
+            continue;
+        }
+
+        match &attachment.body {
+            PartType::Binary(body) => {
+                let part = MimePart::new_binary(content_type, body.as_ref());
+                dest = add_attachment(dest, part, &file_name);
+            }
+            PartType::InlineBinary(body) => match attachment.content_id() {
+                Some(cid) => dest = dest.binary_inline(content_type, cid, body.as_ref()),
+                None => {
+                    let part = MimePart::new_binary(content_type, body.as_ref());
+                    dest = add_attachment(dest, part, &file_name);
+                }
+            },
+            PartType::Text(body) => {
+                let part = MimePart::new_text_other(content_type, body.as_ref());
+                dest = add_attachment(dest, part, &file_name);
+            }
+            _ => (),
+        }
+    }
+    dest
+}
+
+// nested messages and other part types are not copied by rebuild
+fn is_copied_attachment(attachment: &MessagePart, options: &RebuildOptions) -> bool {
+    !options.strip_attachments
+        && is_kept_type(&get_content_type(attachment), &options.keep_types)
+        && (options.redact.is_some()
+            || matches!(
+                attachment.body,
+                PartType::Binary(_) | PartType::InlineBinary(_) | PartType::Text(_)
+            ))
+}
+
+fn expected_attachment_count(source: &Message, options: &RebuildOptions) -> usize {
+    source
+        .attachments()
+        .filter(|attachment| is_copied_attachment(attachment, options))
+        .count()
+        + usize::from(options.attach_original)
+}
+
+// mail_builder writes non-ascii file name as RFC 2047 encoded word, which is
+// not allowed in parameters, so those get RFC 2231 `filename*` instead
+fn add_attachment<'a>(
+    mut dest: MessageBuilder<'a>,
+    part: MimePart<'a>,
+    file_name: &str,
+) -> MessageBuilder<'a> {
+    let part = if file_name.is_ascii() {
+        part.attachment(file_name.to_owned())
+    } else {
+        part.header(
+            "Content-Disposition",
+            HeaderType::Raw(b_headers::raw::Raw::new(attachment_disposition(file_name))),
+        )
+    };
+    dest.attachments.get_or_insert_with(Vec::new).push(part);
+    dest
+}
+
+/// Builds Content-Disposition value with every filename parameter on its own
+/// line. Builder folds raw header at whitespace once 76 bytes are written,
+/// counting folds given to it too, so parameter is folded here only when the
+/// builder would not fold it by itself.
+fn attachment_disposition(file_name: &str) -> String {
+    let mut disposition = String::from("attachment");
+    let mut bytes_written = "Content-Disposition: ".len() + disposition.len();
+    for parameter in rfc2231_parameters("filename", file_name) {
+        disposition.push(';');
+        if bytes_written + 1 >= 76 {
+            disposition.push(' ');
+            bytes_written = 2;
+        } else {
+            disposition.push_str("\r\n\t");
+            bytes_written += 4;
+        }
+        disposition.push_str(&parameter);
+        bytes_written += parameter.len();
+    }
+    disposition
+}
+
+// keeps `\t filename*NN*=` continuation within 73 characters, so it is never
+// folded again and next one is always folded by builder
+const RFC2231_SEGMENT_LENGTH: usize = 57;
+
+// long values are split into `name*0*`, `name*1*`... continuations
+fn rfc2231_parameters(name: &str, value: &str) -> Vec<String> {
+    let mut segments = vec![String::from("utf-8''")];
+    for ch in value.chars() {
+        // multi-byte characters are never split between segments
+        let encoded = match ch {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => ch.to_string(),
+            '!' | '#' | '$' | '&' | '+' | '-' | '.' | '^' | '_' | '`' | '|' | '~' => ch.to_string(),
+            _ => ch
+                .encode_utf8(&mut [0; 4])
+                .bytes()
+                .map(|byte| format!("%{:02X}", byte))
+                .collect(),
+        };
+        let segment = segments.last_mut().unwrap();
+        if segment.len() + encoded.len() > RFC2231_SEGMENT_LENGTH {
+            segments.push(encoded);
+        } else {
+            segment.push_str(&encoded);
+        }
+    }
+    if segments.len() == 1 {
+        return vec![format!("{}*={}", name, segments[0])];
+    }
+    segments
+        .iter()
+        .enumerate()
+        .map(|(index, segment)| format!("{}*{}*={}", name, index, segment))
+        .collect()
+}
@@ -1,58 +1,387 @@
-use base64::{engine::general_purpose, Engine as _};
-use clap::{arg, Command};
-use comrak::{markdown_to_html, ComrakOptions};
-use mail_builder::headers as b_headers;
-use mail_builder::headers::HeaderType;
+use clap::{arg, ArgAction, Command};
+use enrichmail::{
+    check_html_size, debug_html, detect_language, escape_mbox_content, get_all_addresses,
+    get_attachment_hash, get_attachment_name, get_attachment_type_summary, get_bounce_info,
+    get_builder_from_parser, get_builder_message_id, get_cleanup_summary, get_content_languages,
+    get_content_type, get_corrupted_attachments, get_dmarc_result, get_email_content,
+    get_email_headers, get_envelope_from, get_file_name, get_front_matter, get_links, get_list_id,
+    get_mbox_separator, get_message_content_type, get_mime_tree, get_pixel_base_url,
+    get_pixel_element, get_priority, get_received_chain, get_spam_score, get_spoofed_domain,
+    get_thread_index, get_user_agent, human_size, is_signed_or_encrypted, msg_to_eml,
+    parse_addresses, put_email_on_imap_server, split_mbox, text_body_as_html,
+    text_body_as_html_fragment, to_org, verify_rebuild, write_message, BodyOptions, DkimOptions,
+    FrontMatter, HashAlgorithm, HtmlOptions, ImapAuth, ImapOptions, MarkdownExtension,
+    RebuildOptions, TextEncoding, MD_FLAGS,
+};
 use mail_builder::MessageBuilder;
-use mail_parser::{Addr, HeaderName, HeaderValue, Message, MessagePart, PartType, RfcHeader};
-use std::fs::File;
-use std::io::prelude::*;
+use mail_parser::{DateTime, HeaderValue, Message, PartType};
+use regex::Regex;
+use std::io::{IsTerminal, Write};
 use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
 
 fn cli() -> Command {
     Command::new("cargo")
         .about("Email enrich tool for mutt")
         .args(vec![
-            arg!(<FILE> "path to email file  (use '-' for stdin)"),
+            arg!([FILE] "path to email file, directory or glob  (use '-' for stdin)")
+                .required_unless_present("files-from"),
+            arg!(--"files-from" <PATH> "Read newline separated list of email files from PATH"),
             arg!(--"get-message-id" "Prints message id of given mail"),
+            arg!(--"with-brackets" "Wrap printed message id in angle brackets")
+                .requires("get-message-id"),
+            arg!(--delimiter <STR> "Separator printed between values of list producing flags (default newline)"),
+            arg!(--color <WHEN> "Color human readable output, values meant for scripts stay plain")
+                .value_parser(["auto", "always", "never"])
+                .default_value("auto"),
             arg!(--"get-subject" "Prints subject of given mail"),
             arg!(--"get-from-email" "Prints from email of given mail"),
+            arg!(--"get-all-addresses" "Prints every address from address headers, one per line"),
+            arg!(--"check-spoof" "Fails when From display name mentions other domain than its address"),
+            arg!(--"get-envelope-from" "Prints Return-Path address, From one when missing"),
+            arg!(--"get-list-id" "Prints mailing list id of given mail"),
+            arg!(--"get-thread-index" "Prints conversation GUID from Thread-Index header"),
+            arg!(--"get-dmarc" "Prints pass, fail or none from DMARC result in Authentication-Results"),
+            arg!(--"get-bounce" "Prints failed recipient and status of delivery status notification"),
+            arg!(--"get-priority" "Prints high, normal or low from priority headers"),
+            arg!(--"get-size" "Prints size of given mail in bytes"),
+            arg!(--human "Print size with binary units, eg. 1.2 MiB").requires("get-size"),
+            arg!(--"get-spam-score" "Prints score from X-Spam-Score or X-Spam-Status header"),
+            arg!(--"get-user-agent" "Prints sending client from User-Agent or X-Mailer header"),
+            arg!(--"list-links" "Prints urls from text and html body, one per line"),
+            arg!(--"get-content-language" "Prints language tags from Content-Language header, one per line"),
+            arg!(--"detect-language" "Prints ISO 639-1 code of text body language, und when unsure"),
+            arg!(--"get-content-type" "Prints top-level content type of given mail"),
+            arg!(--"get-received-chain" "Prints Received headers of given mail, one per line"),
+            arg!(--"get-raw-header" <NAME> "Prints undecoded value of given header"),
+            arg!(--"verify-attachments" "Checks that every attachment decodes correctly"),
+            arg!(--"attachment-hashes" <ALGO> "Prints hash of every attachment")
+                .value_parser(["sha256", "md5"]),
+            arg!(--"mime-tree" "Prints MIME structure with content type, disposition and size of parts"),
+            arg!(--"attachment-type-summary" "Prints number of attachments per content type"),
+            arg!(--"get-attachment-names" "Prints file name of every attachment, one per line"),
+            arg!(--"charset-detect" "Detect text body charset when missing or wrongly declared"),
+            arg!(--"body-file" <PATH> "Use markdown from PATH as text body"),
+            arg!(--"force-decode-body" "Base64 decode text body that looks base64 despite its encoding"),
             arg!(--"html-preview" "Generate html from markdown in text body and prints it"),
+            arg!(--fragment "Print only converted body without html document wrapper")
+                .requires("html-preview"),
+            arg!(--"inline-cid" "Embed cid: images of previewed html as data: uris")
+                .requires("html-preview"),
+            arg!(--"strip-quotes" "Remove quoted reply and its attribution from generated html"),
+            arg!(--"keep-one-quote-level" "Keep first level of quoted reply with --strip-quotes")
+                .requires("strip-quotes"),
+            arg!(--"max-body-bytes" <N> "Truncate previewed text body to N bytes")
+                .value_parser(clap::value_parser!(usize))
+                .requires("html-preview"),
+            arg!(--"to-org" "Prints email as org-mode entry"),
+            arg!(--"debug-html" "Prints original html body and generated one below it, separated by <hr>"),
             arg!(--"generate-html" "Generate html body from markdown in text body"),
+            arg!(--"no-html" "Output only text body, overrides --generate-html and --keep-original-html"),
+            arg!(--"self-reference" "Append own message id to References header"),
+            arg!(--"new-message-id" "Generate new Message-ID instead of keeping the original one"),
+            arg!(--"keep-return-path" "Copy Return-Path header, it is dropped by default"),
+            arg!(--"drop-sender" "Do not copy Sender header"),
+            arg!(--"normalize-header-case" "Rewrite header names to canonical casing"),
+            arg!(--"dedupe-headers" "Keep only first occurrence of headers allowed once"),
+            arg!(--"subject-template" <TEMPLATE> "Rewrite subject, {subject} is replaced with the original one"),
+            arg!(--"format-flowed" "Reformat text body as format=flowed (RFC 3676)"),
+            arg!(--"text-encoding" <ENCODING> "Transfer encoding of text body, chosen automatically by default")
+                .value_parser(["7bit", "quoted-printable", "base64"]),
+            arg!(--"dkim-key" <PATH> "Sign rebuilt email with DKIM using RSA private key from PATH")
+                .requires("dkim-selector")
+                .requires("dkim-domain"),
+            arg!(--"dkim-selector" <SELECTOR> "DKIM selector of the signing key").requires("dkim-key"),
+            arg!(--"dkim-domain" <DOMAIN> "DKIM signing domain").requires("dkim-key"),
+            arg!(--"passthrough-signed" "Output signed or encrypted email unchanged instead of failing"),
+            arg!(--"rebuild-only" "Print email rebuilt without any enrichment, to diff with input"),
+            arg!(--"verify-rebuild" "Fail when rebuilt email differs from input in subject, from or attachment count"),
+            arg!(--"preserve-structure" "Output email unchanged when no option modifies it"),
+            arg!(--"keep-original-html" "Keep existing html body instead of generating new one"),
+            arg!(--"attach-original" "Attach untouched input email as original.eml"),
+            arg!(--"strip-attachments" "Drop all attachments from rebuilt email"),
+            arg!(--"keep-types" <TYPES> "Comma separated content types of attachments to keep (e.g. image/*)")
+                .value_delimiter(','),
+            arg!(--"no-pre-markdown" "Use text body as standard markdown without forced line breaks"),
+            arg!(--"heading-offset" <N> "Shift markdown heading levels down by N")
+                .value_parser(clap::value_parser!(u8))
+                .default_value("0"),
+            arg!(--emoji "Replace :shortcode: emoji in text body before conversion"),
+            arg!(--"detect-tables" "Convert pipe separated or column aligned text into tables"),
+            arg!(--"break-on" <REGEX> "Force line breaks only after lines matching REGEX, code fences included")
+                .value_parser(Regex::new)
+                .conflicts_with("no-pre-markdown"),
+            arg!(--"md-flags" <FLAGS> "Comma separated markdown extensions to enable")
+                .value_delimiter(',')
+                .value_parser(MD_FLAGS.map(|(name, _)| name)),
+            arg!(--linkify "Turn bare http(s) urls in text body into links"),
+            arg!(--tables "Enable GFM tables in markdown"),
+            arg!(--"minify-html" "Strip indentation and whitespace between tags of generated html"),
+            arg!(--"max-html-size" <BYTES> "Limit size of generated html document")
+                .value_parser(clap::value_parser!(usize)),
+            arg!(--"oversized-html" <ACTION> "What to do with generated html over --max-html-size")
+                .value_parser(["truncate", "abort"])
+                .default_value("truncate"),
+            arg!(--"strip-html-comments" "Remove comments from generated or kept original html"),
+            arg!(--"strip-pixels" "Remove tracking pixels from kept original html"),
+            arg!(--"remove-remote-images" "Remove remote images from kept original html, unless proxied"),
+            arg!(--detox "Keep original html without comments, tracking pixels and remote images, report removed ones"),
+            arg!(--"proxy-images" <BASE_URL> "Load remote images of generated or kept original html through proxy"),
+            arg!(--"inline-css" "Move styles into style attributes of generated html"),
             arg!(--"add-pixel" <BASE_URL> "Add tracking pixel to html body")
                 .requires("generate-html"),
-            arg!(--"put-on-imap" <MAILBOX> "Put email on IMAP server")
+            arg!(--"pixel-base-from-headers" "Add tracking pixel with base url taken from email headers")
+                .requires("generate-html")
+                .conflicts_with("add-pixel"),
+            arg!(--"pixel-fallback" <BASE_URL> "Add second tracking pixel pointing at given host")
+                .requires("generate-html"),
+            arg!(--"pixel-id" <VALUE> "Tracking pixel id, Message-ID is used by default"),
+            arg!(--"pixel-host-header" <NAME> "Header with pixel host")
+                .default_value("X-Forwarded-Host"),
+            arg!(--"pixel-proto-header" <NAME> "Header with pixel scheme")
+                .default_value("X-Forwarded-Proto"),
+            arg!(--"put-on-imap" <MAILBOX> "Put email on IMAP server, {YYYY}, {MM} and {DD} in MAILBOX come from its date")
                 .requires("server")
-                .requires("port")
                 .requires("user")
                 .requires("password"),
             arg!(--server <SERVER> "IMAP server uri"),
-            arg!(--port <PORT> "IMAP server port"),
+            arg!(--port <PORT> "IMAP server port, 993 when omitted")
+                .value_parser(clap::value_parser!(u16)),
             arg!(--user <USER> "IMAP user name"),
             arg!(--password <PASS> "IMAP password"),
+            arg!(--"imap-deadline" <MS> "Abort IMAP upload not finished within MS milliseconds")
+                .value_parser(clap::value_parser!(u64)),
+            arg!(--"imap-separator" <CHAR> "Replace '/' in mailbox with server hierarchy separator")
+                .value_parser(clap::value_parser!(char)),
+            arg!(--"imap-move-existing" <DEST> "Move copies with the same Message-ID to DEST before upload")
+                .requires("put-on-imap"),
+            arg!(--"imap-auth" <MECHANISM> "IMAP authentication mechanism")
+                .value_parser(["LOGIN", "PLAIN", "XOAUTH2"])
+                .default_value("LOGIN"),
+            arg!(--redact "Replace addresses, given headers and attachments with placeholders"),
+            arg!(--"redact-headers" <HEADERS> "Comma separated list of headers to redact")
+                .value_delimiter(',')
+                .requires("redact"),
+            arg!(--"to-override" <ADDR> "Replace To recipients with given address")
+                .action(ArgAction::Append),
+            arg!(--"cc-override" <ADDR> "Replace Cc recipients with given address")
+                .action(ArgAction::Append),
+            arg!(--"output-format" <FORMAT> "Format of rebuilt email")
+                .value_parser(["eml", "mbox"])
+                .default_value("eml"),
+            arg!(--"in-place" "Write rebuilt email back to FILE instead of printing it")
+                .conflicts_with("mbox"),
+            arg!(--"save-to" <DIR> "Also save output email as uniquely named .eml file in DIR"),
+            arg!(--mbox "Treat FILE as mbox and process every message in it"),
+            arg!(--msg "Treat FILE as Outlook .msg and process it as email")
+                .conflicts_with_all(["mbox", "in-place"]),
+            arg!(--since <DATE> "Skip mbox messages dated before DATE (YYYY-MM-DD or RFC 3339)")
+                .value_parser(parse_date_argument)
+                .requires("mbox"),
+            arg!(--before <DATE> "Skip mbox messages dated on or after DATE")
+                .value_parser(parse_date_argument)
+                .requires("mbox"),
+            arg!(--"no-date-policy" <POLICY> "What to do with mbox messages without date")
+                .value_parser(["include", "exclude"])
+                .default_value("include")
+                .requires("mbox"),
         ])
 }
 
+struct Options {
+    mbox: bool,
+    msg: bool,
+    mbox_output: bool,
+    in_place: bool,
+    save_to: Option<String>,
+    since: Option<i64>,
+    before: Option<i64>,
+    include_undated: bool,
+    get_message_id: bool,
+    with_brackets: bool,
+    delimiter: String,
+    get_subject: bool,
+    get_from_email: bool,
+    get_list_id: bool,
+    get_content_type: bool,
+    get_thread_index: bool,
+    get_spam_score: bool,
+    get_user_agent: bool,
+    get_envelope_from: bool,
+    get_dmarc: bool,
+    get_priority: bool,
+    get_size: bool,
+    human: bool,
+    get_bounce: bool,
+    mime_tree: bool,
+    check_spoof: bool,
+    color: bool,
+    color_stderr: bool,
+    get_content_language: bool,
+    detect_language: bool,
+    get_all_addresses: bool,
+    list_links: bool,
+    get_received_chain: bool,
+    get_raw_header: Option<String>,
+    verify_attachments: bool,
+    attachment_hashes: Option<HashAlgorithm>,
+    attachment_type_summary: bool,
+    get_attachment_names: bool,
+    html_preview: bool,
+    fragment: bool,
+    debug_html: bool,
+    to_org: bool,
+    generate_html: bool,
+    no_html: bool,
+    passthrough_signed: bool,
+    preserve_structure: bool,
+    rebuild_only: bool,
+    detox: bool,
+    verify_rebuild: bool,
+    add_pixel: Option<String>,
+    // host and scheme header names
+    pixel_headers: Option<(String, String)>,
+    pixel_id: Option<String>,
+    pixel_fallback: Option<String>,
+    imap: Option<ImapOptions>,
+    imap_deadline: Option<Duration>,
+    html: HtmlOptions,
+    rebuild: RebuildOptions,
+}
+
+impl Options {
+    // these only need headers, so body does not have to be read
+    fn is_header_query(&self) -> bool {
+        self.get_message_id
+            || self.get_subject
+            || self.get_from_email
+            || self.get_list_id
+            || self.get_content_type
+            || self.get_thread_index
+            || self.get_spam_score
+            || self.get_user_agent
+            || self.get_envelope_from
+            || self.get_dmarc
+            || self.get_priority
+            || self.check_spoof
+            || self.get_content_language
+            || self.get_all_addresses
+            || self.get_received_chain
+            || self.get_raw_header.is_some()
+    }
+
+    // whether rebuilt message differs from the original in more than structure
+    fn modifies_message(&self, message: &Message) -> bool {
+        let rebuild = &self.rebuild;
+        let has_html = message
+            .html_part(0)
+            .is_some_and(|part| matches!(part.body, PartType::Html(_)));
+        self.generate_html
+            || (self.no_html && has_html)
+            || rebuild.body.force_decode
+            || rebuild.body.text.is_some()
+            || rebuild.redact.is_some()
+            || !rebuild.to_override.is_empty()
+            || !rebuild.cc_override.is_empty()
+            || rebuild.format_flowed
+            || rebuild.self_reference
+            || rebuild.new_message_id
+            || rebuild.body.charset_detect
+            || rebuild.strip_attachments
+            || !rebuild.keep_types.is_empty()
+            || rebuild.subject_template.is_some()
+            || rebuild.dedupe_headers
+            || rebuild.drop_sender
+            || rebuild.attach_original
+            || rebuild.normalize_header_case
+            || rebuild.strip_html_comments
+            || rebuild.proxy_images.is_some()
+            || rebuild.strip_pixels
+            || rebuild.remove_remote_images
+            || rebuild.text_encoding.is_some()
+            || rebuild.dkim.is_some()
+            // front-matter is removed from the text body
+            || get_front_matter(message, &rebuild.body) != FrontMatter::default()
+    }
+}
+
 fn main() {
     let matches = cli().get_matches();
+    let options = get_options(&matches);
 
-    let file = matches
+    let mut file_paths = matches
         .get_one::<String>("FILE")
-        .map_or_else(|| panic!("No email file provided"), get_email_content);
+        .map_or_else(Vec::new, get_email_paths);
+    if let Some(list_path) = matches.get_one::<String>("files-from") {
+        file_paths.extend(get_listed_paths(list_path));
+    }
 
-    let message = Message::parse(file.as_slice()).unwrap();
+    if options.in_place && file_paths.iter().any(|path| path == "-") {
+        eprintln!("--in-place can not be used with stdin");
+        std::process::exit(1);
+    }
 
-    if matches.get_flag("get-message-id") {
-        println!("{}", message.message_id().unwrap_or(""));
-        return;
+    let mut emails_to_store = vec![];
+    for file_path in file_paths {
+        let file = if options.msg {
+            get_email_content(&file_path).and_then(|content| msg_to_eml(&content))
+        } else if options.is_header_query() && !options.mbox {
+            get_email_headers(&file_path)
+        } else {
+            get_email_content(&file_path)
+        }
+        .unwrap_or_else(|error| {
+            eprintln!("{}: {}", file_path, error);
+            std::process::exit(1);
+        });
+        if options.mbox {
+            for (index, (separator, content)) in split_mbox(&file).iter().enumerate() {
+                if let Some(eml) = process_email(content, Some(separator), None, &options) {
+                    emails_to_store.push((format!("{}#{}", file_path, index + 1), eml));
+                }
+            }
+        } else if let Some(eml) = process_email(
+            &file,
+            None,
+            options.in_place.then_some(file_path.as_str()),
+            &options,
+        ) {
+            emails_to_store.push((file_path, eml));
+        }
     }
 
-    if matches.get_flag("get-subject") {
+    if let Some(imap_options) = &options.imap {
+        handle_put_email_on_imap_server(emails_to_store, imap_options, options.imap_deadline);
+    }
+}
+
+fn process_email(
+    file: &[u8],
+    mbox_separator: Option<&str>,
+    in_place_path: Option<&str>,
+    options: &Options,
+) -> Option<Vec<u8>> {
+    let message = Message::parse(file).unwrap();
+
+    if !is_within_date_range(&message, options) {
+        return None;
+    }
+
+    if options.get_message_id {
+        match message.message_id() {
+            Some(id) if options.with_brackets => println!("<{}>", id),
+            id => println!("{}", id.unwrap_or("")),
+        }
+        return None;
+    }
+
+    if options.get_subject {
         println!("{}", message.subject().unwrap_or(""));
-        return;
+        return None;
     }
 
-    if matches.get_flag("get-from-email") {
+    if options.get_from_email {
         match message.from() {
             HeaderValue::Address(from) => {
                 let email = from
@@ -63,242 +392,835 @@ fn main() {
             }
             _ => println!(),
         }
-        return;
+        return None;
     }
 
-    if matches.get_flag("html-preview") {
-        println!("{}", text_body_as_html(&message, None));
-        return;
+    if options.get_all_addresses {
+        print_values(get_all_addresses(&message), &options.delimiter);
+        return None;
     }
 
-    let mut eml = get_builder_from_parser(&message);
+    if options.check_spoof {
+        if let Some(domain) = get_spoofed_domain(&message) {
+            let warning = format!(
+                "Suspicious From: display name mentions {}, which differs from address domain",
+                domain
+            );
+            eprintln!("{}", paint(&warning, ERROR_COLOR, options.color_stderr));
+            std::process::exit(1);
+        }
+        return None;
+    }
 
-    handle_put_email_on_imap_server(&eml, &message, &matches);
+    if options.get_envelope_from {
+        if let Some(envelope_from) = get_envelope_from(&message) {
+            println!("{}", envelope_from);
+        }
+        return None;
+    }
 
-    let append = matches
-        .get_one::<String>("add-pixel")
-        .map(|tracking_url| get_pixel_element(tracking_url, &message));
+    if options.get_list_id {
+        if let Some(list_id) = get_list_id(&message) {
+            println!("{}", list_id);
+        }
+        return None;
+    }
 
-    if matches.get_flag("generate-html") {
-        eml = eml.html_body(text_body_as_html(&message, append));
+    if options.get_content_language {
+        print_values(get_content_languages(&message), &options.delimiter);
+        return None;
     }
 
-    println!("{}", eml.write_to_string().unwrap());
-}
+    if options.detect_language {
+        println!("{}", detect_language(&message, &options.html.body));
+        return None;
+    }
 
-fn text_body(message: &Message) -> String {
-    message.body_text(0).unwrap().to_string()
-}
+    if options.get_content_type {
+        println!("{}", get_message_content_type(&message));
+        return None;
+    }
+
+    if options.get_thread_index {
+        if let Some(thread_index) = get_thread_index(&message) {
+            println!("{}", thread_index);
+        }
+        return None;
+    }
+
+    if options.get_dmarc {
+        println!("{}", get_dmarc_result(&message));
+        return None;
+    }
+
+    if options.get_bounce {
+        let bounces = get_bounce_info(&message)
+            .into_iter()
+            .map(|(recipient, status)| format!("{} {}", recipient, status));
+        print_values(bounces, &options.delimiter);
+        return None;
+    }
+
+    if options.get_priority {
+        println!("{}", get_priority(&message));
+        return None;
+    }
+
+    if options.get_size {
+        if options.human {
+            println!("{}", human_size(file.len()));
+        } else {
+            println!("{}", file.len());
+        }
+        return None;
+    }
+
+    if options.get_spam_score {
+        if let Some(spam_score) = get_spam_score(&message) {
+            println!("{}", spam_score);
+        }
+        return None;
+    }
+
+    if options.get_user_agent {
+        if let Some(user_agent) = get_user_agent(&message) {
+            println!("{}", user_agent);
+        }
+        return None;
+    }
+
+    if options.list_links {
+        print_values(get_links(&message), &options.delimiter);
+        return None;
+    }
+
+    if options.get_received_chain {
+        print_values(get_received_chain(&message), &options.delimiter);
+        return None;
+    }
+
+    if let Some(name) = &options.get_raw_header {
+        let values = message
+            .headers_raw()
+            .filter(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim());
+        print_values(values, &options.delimiter);
+        return None;
+    }
+
+    if options.verify_attachments {
+        let corrupted = get_corrupted_attachments(&message);
+        let lines = corrupted.iter().map(|attachment| {
+            let key = format!(
+                "{} ({})",
+                get_file_name(attachment),
+                get_content_type(attachment)
+            );
+            format!(
+                "{}: {}",
+                paint(&key, KEY_COLOR, options.color),
+                paint("unable to decode", ERROR_COLOR, options.color)
+            )
+        });
+        print_values(lines, &options.delimiter);
+        if !corrupted.is_empty() {
+            std::process::exit(1);
+        }
+        return None;
+    }
+
+    if let Some(algorithm) = options.attachment_hashes {
+        let hashes = message.attachments().map(|attachment| {
+            let key = format!(
+                "{} ({})",
+                get_file_name(attachment),
+                get_content_type(attachment)
+            );
+            format!(
+                "{}: {}",
+                paint(&key, KEY_COLOR, options.color),
+                get_attachment_hash(attachment, algorithm)
+            )
+        });
+        print_values(hashes, &options.delimiter);
+        return None;
+    }
+
+    if options.get_attachment_names {
+        let names = message
+            .attachments()
+            .enumerate()
+            .map(|(index, attachment)| get_attachment_name(attachment, index));
+        print_values(names, &options.delimiter);
+        return None;
+    }
+
+    if options.mime_tree {
+        let lines = get_mime_tree(&message).into_iter().map(|line| {
+            // content type follows indentation, eg. `  text/plain (5 bytes)`
+            let content_start = line.len() - line.trim_start().len();
+            let content_end = line[content_start..]
+                .find(' ')
+                .map_or(line.len(), |end| content_start + end);
+            format!(
+                "{}{}{}",
+                &line[..content_start],
+                paint(&line[content_start..content_end], KEY_COLOR, options.color),
+                &line[content_end..]
+            )
+        });
+        print_values(lines, &options.delimiter);
+        return None;
+    }
+
+    if options.attachment_type_summary {
+        let summary =
+            get_attachment_type_summary(&message)
+                .into_iter()
+                .map(|(content_type, count)| {
+                    format!(
+                        "{}: {}",
+                        paint(&content_type, KEY_COLOR, options.color),
+                        count
+                    )
+                });
+        print_values(summary, &options.delimiter);
+        return None;
+    }
+
+    if options.to_org {
+        print!("{}", to_org(&message, &options.html.body));
+        return None;
+    }
+
+    if options.debug_html {
+        println!(
+            "{}",
+            html_or_exit(debug_html(&message, &options.html), options)
+        );
+        return None;
+    }
+
+    if options.html_preview && options.fragment {
+        println!("{}", text_body_as_html_fragment(&message, &options.html));
+        return None;
+    }
+
+    if options.html_preview {
+        println!(
+            "{}",
+            html_or_exit(text_body_as_html(&message, None, &options.html), options)
+        );
+        return None;
+    }
+
+    if options.rebuild_only {
+        let rebuild_options = RebuildOptions::default();
+        let eml = get_builder_from_parser(&message, &rebuild_options);
+        print_bytes(&write_message_or_exit(eml, &rebuild_options));
+        return None;
+    }
+
+    if is_signed_or_encrypted(&message) {
+        if !options.passthrough_signed {
+            eprintln!("Signed or encrypted email would be broken by rebuild, use --passthrough-signed to output it unchanged");
+            std::process::exit(1);
+        }
+        print_email(file, mbox_separator, in_place_path, &message, options);
+        return options.imap.as_ref().map(|_| file.to_vec());
+    }
+
+    if options.preserve_structure && !options.modifies_message(&message) {
+        print_email(file, mbox_separator, in_place_path, &message, options);
+        return options.imap.as_ref().map(|_| file.to_vec());
+    }
+
+    if options.detox {
+        let summary = get_cleanup_summary(&message, &options.rebuild);
+        let count = |count: usize| paint(&count.to_string(), COUNT_COLOR, options.color_stderr);
+        eprintln!(
+            "Detox removed {} tracking pixels, {} remote images and {} html comments, proxied {} images",
+            count(summary.pixels),
+            count(summary.remote_images),
+            count(summary.comments),
+            count(summary.proxied_images)
+        );
+    }
 
-fn pre_markdown(text: &str) -> String {
-    let mut result = String::new();
-    text.lines().for_each(|line| {
-        // append two spaces to force line break
-        result.push_str(&format!("{line}  \n"));
+    let mut eml = get_builder_from_parser(&message, &options.rebuild);
+
+    let email_to_store = get_email_to_store(&eml, &message, options);
+
+    let tracking_url = get_front_matter(&message, &options.html.body)
+        .pixel_url
+        .or_else(|| options.add_pixel.clone())
+        .or_else(|| {
+            options
+                .pixel_headers
+                .as_ref()
+                .and_then(|(host, proto)| get_pixel_base_url(&message, host, proto))
+        });
+    if tracking_url.is_none() && options.pixel_fallback.is_some() {
+        eprintln!("--pixel-fallback needs main tracking pixel from --add-pixel, --pixel-base-from-headers or front matter pixel_url");
+        std::process::exit(1);
+    }
+    let append = tracking_url.map(|tracking_url| {
+        let mut tracking_urls = vec![tracking_url.as_str()];
+        tracking_urls.extend(options.pixel_fallback.as_deref());
+        let id = options
+            .pixel_id
+            .as_deref()
+            .or_else(|| get_builder_message_id(&eml))
+            .unwrap_or_else(|| {
+                eprintln!(
+                    "Unable to identify tracking pixel, Message-ID is redacted, use --pixel-id"
+                );
+                std::process::exit(1);
+            });
+        get_pixel_element(&tracking_urls, id)
     });
-    result
-}
 
-fn text_body_as_html(message: &Message, append: Option<String>) -> String {
-    let body = markdown_to_html(
-        &pre_markdown(&text_body(message)),
-        &ComrakOptions::default(),
-    );
-    let body_append = append.map_or_else(String::new, |append| append);
-    format!(
-        r#"
-        <html>
-            <head>
-            <meta http-equiv="Content-Type" content="text/html charset=UTF-8" />
-            <meta name="generator" content="mutt-html-markdown/0.1" />
-            <style>
-                code {{ margin-left: 20px; background: #ddd; display: inline-block; padding: 10px 16px; font-family: monospace; }}
-                blockquote {{ white-space: normal; border-left: 10px solid #ddd; margin-left: 0; padding-left: 10px }}
-            </style>
-            </head>
-            <body>
-                {}
-                {}
-            </body>
-        </html>
-        "#,
-        body, body_append
-    )
+    if options.generate_html && eml.html_body.is_none() {
+        let html = text_body_as_html(&message, append, &options.html);
+        eml = eml.html_body(html_or_exit(html, options));
+    }
+
+    let output = write_message_or_exit(eml, &options.rebuild);
+    if options.verify_rebuild {
+        let mismatches = verify_rebuild(&message, &output, &options.rebuild);
+        if !mismatches.is_empty() {
+            mismatches
+                .iter()
+                .for_each(|mismatch| eprintln!("{}", mismatch));
+            std::process::exit(1);
+        }
+    }
+    print_email(&output, mbox_separator, in_place_path, &message, options);
+    email_to_store
 }
 
-fn transform_address<'a>(address: &'a Addr) -> b_headers::address::Address<'a> {
-    let name = address.name.as_ref().map(AsRef::as_ref);
-    b_headers::address::Address::new_address(name, address.address.as_ref().unwrap().clone())
+// ANSI SGR codes used for human readable output
+const KEY_COLOR: &str = "36";
+const COUNT_COLOR: &str = "1";
+const ERROR_COLOR: &str = "31";
+
+/// Tells whether output written to `stream` gets colored, `auto` colors only
+/// terminal.
+fn use_color(matches: &clap::ArgMatches, stream: impl IsTerminal) -> bool {
+    match matches.get_one::<String>("color").map(String::as_str) {
+        Some("always") => true,
+        Some("never") => false,
+        _ => stream.is_terminal(),
+    }
 }
 
-fn copy_headers<'a>(mut dest: MessageBuilder<'a>, source: &'a Message) -> MessageBuilder<'a> {
-    for header in source.headers() {
-        let maybe_header = match header.value() {
-            HeaderValue::Address(address) => Some(HeaderType::Address(transform_address(address))),
-            HeaderValue::Text(text) => {
-                Some(HeaderType::Text(b_headers::text::Text::new(text.as_ref())))
-            }
-            HeaderValue::DateTime(datetime) => Some(HeaderType::Date(b_headers::date::Date::new(
-                datetime.to_timestamp(),
-            ))),
-            // content will be generated automatically, it will mess up email if copied here
-            HeaderValue::ContentType(_) => None,
-            HeaderValue::AddressList(addresses) => {
-                let mut new_addresses = vec![];
-                for address in addresses.iter() {
-                    let new_address = transform_address(address);
-                    new_addresses.push(new_address);
-                }
-                Some(HeaderType::Address(b_headers::address::Address::List(
-                    new_addresses,
-                )))
-            }
-            HeaderValue::Group(group) => todo!("Group not implemented {:?}", group),
-            HeaderValue::GroupList(group_list) => {
-                todo!("Group list not implemented {:?}", group_list)
-            }
-            HeaderValue::TextList(text_list) => {
-                let text = text_list.join("\t\n");
-                Some(HeaderType::Text(b_headers::text::Text::new(text)))
-            }
-            HeaderValue::Empty => todo!("Empty not implemented"),
-        };
-        if let Some(new_header) = maybe_header {
-            dest = dest.header(header.name(), new_header);
-        };
+fn paint(text: &str, code: &str, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_owned()
     }
-    dest
 }
 
-fn get_email_content(file_path: &String) -> Vec<u8> {
-    if file_path == "-" {
-        let stdin = std::io::stdin();
-        let mut input = String::new();
+/// Prints values joined by delimiter, nothing when there are none.
+fn print_values<T: std::fmt::Display>(values: impl IntoIterator<Item = T>, delimiter: &str) {
+    let values: Vec<String> = values.into_iter().map(|value| value.to_string()).collect();
+    if !values.is_empty() {
+        println!("{}", values.join(delimiter));
+    }
+}
 
-        while let Ok(n) = stdin.read_line(&mut input) {
-            if n == 0 {
-                break;
+fn print_email(
+    output: &[u8],
+    mbox_separator: Option<&str>,
+    in_place_path: Option<&str>,
+    message: &Message,
+    options: &Options,
+) {
+    if let Some(dir) = &options.save_to {
+        save_email(dir, message, output);
+    }
+    let mbox_separator = mbox_separator
+        .map(str::to_owned)
+        .or_else(|| options.mbox_output.then(|| get_mbox_separator(message)));
+    let output = match mbox_separator {
+        Some(separator) => {
+            let mut mbox = format!("{}\n", separator).into_bytes();
+            mbox.extend(escape_mbox_content(output));
+            // next separator has to follow an empty line
+            if !(mbox.ends_with(b"\n\n") || mbox.ends_with(b"\r\n\r\n")) {
+                mbox.push(b'\n');
             }
+            mbox
         }
-        input.as_bytes().to_vec()
-    } else {
-        let mut file_content = vec![];
-        let path = Path::new(file_path);
-        let mut fh = File::open(path).expect("Unable to open file");
-        fh.read_to_end(&mut file_content).expect("Unable to read");
-        file_content
+        None => output.to_vec(),
+    };
+    match in_place_path {
+        Some(path) => write_in_place(path, &output),
+        None => print_bytes(&output),
     }
 }
 
-fn get_pixel_element(tracking_url: &String, message: &Message) -> String {
-    let encoded_id: String = general_purpose::STANDARD_NO_PAD.encode(message.message_id().unwrap());
-    let pixel_url = format!("{}/image/{}.gif", tracking_url, encoded_id);
-    format!(
-        r#"
-        <img src="{}" alt="Open pixel" style="border: 0px; width: 0px; max-width: 1px;" />
-        "#,
-        pixel_url
-    )
+// raw bytes, so 8bit content of the email is not altered
+fn print_bytes(output: &[u8]) {
+    std::io::stdout()
+        .lock()
+        .write_all(output)
+        .expect("Unable to write output");
 }
 
-fn put_email_on_imap_server(
-    eml: MessageBuilder,
-    mailbox: &String,
-    server: &String,
-    port: u16,
-    user: &String,
-    pass: &String,
-) {
-    let tls = native_tls::TlsConnector::builder().build().unwrap();
-    let client = imap::connect((server.clone(), port), server, &tls).unwrap();
-    let mut imap_session = client.login(user, pass).map_err(|e| e.0).unwrap();
-
-    imap_session
-        .append_with_flags(
-            mailbox,
-            eml.write_to_vec().unwrap(),
-            &[imap::types::Flag::Seen],
-        )
+fn save_email(dir: &str, message: &Message, content: &[u8]) {
+    std::fs::create_dir_all(dir).expect("Unable to create save directory");
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let id: String = message
+        .message_id()
+        .unwrap_or("no-message-id")
+        .chars()
+        .map(|ch| match ch {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' | '@' => ch,
+            _ => '_',
+        })
+        .collect();
+    // same message saved twice within a second gets a counter
+    let path = (0..)
+        .map(|counter| match counter {
+            0 => Path::new(dir).join(format!("{}.{}.eml", timestamp, id)),
+            _ => Path::new(dir).join(format!("{}.{}.{}.eml", timestamp, id, counter)),
+        })
+        .find(|path| !path.exists())
         .unwrap();
+    std::fs::write(path, content).expect("Unable to save email");
 }
 
-fn get_builder_from_parser<'a>(message: &'a Message) -> MessageBuilder<'a> {
-    let mut eml = MessageBuilder::new().text_body(text_body(message));
-    eml = copy_headers(eml, message);
-    eml = copy_attachments(eml, message);
-    eml
+// temp file in the same directory, so rename does not cross filesystems
+fn write_in_place(path: &str, content: &[u8]) {
+    let tmp_path = format!("{}.enrichmail.tmp", path);
+    let permissions = std::fs::metadata(path)
+        .expect("Unable to read file permissions")
+        .permissions();
+    let mut tmp_file = std::fs::File::create(&tmp_path).expect("Unable to write temporary file");
+    // before writing, so private mail is never readable with default mode
+    tmp_file
+        .set_permissions(permissions)
+        .and_then(|_| tmp_file.write_all(content))
+        .expect("Unable to write temporary file");
+    std::fs::rename(&tmp_path, path).expect("Unable to replace file");
 }
 
-fn handle_put_email_on_imap_server(
-    eml: &MessageBuilder,
-    message: &Message,
-    matches: &clap::ArgMatches,
-) {
+fn get_options(matches: &clap::ArgMatches) -> Options {
+    let body = get_body_options(matches);
+    Options {
+        mbox: matches.get_flag("mbox"),
+        msg: matches.get_flag("msg"),
+        in_place: matches.get_flag("in-place"),
+        save_to: matches.get_one::<String>("save-to").cloned(),
+        mbox_output: matches
+            .get_one::<String>("output-format")
+            .map(String::as_str)
+            == Some("mbox"),
+        since: matches.get_one::<i64>("since").copied(),
+        before: matches.get_one::<i64>("before").copied(),
+        include_undated: matches
+            .get_one::<String>("no-date-policy")
+            .map(String::as_str)
+            == Some("include"),
+        get_message_id: matches.get_flag("get-message-id"),
+        with_brackets: matches.get_flag("with-brackets"),
+        delimiter: matches
+            .get_one::<String>("delimiter")
+            .map_or_else(|| "\n".to_string(), String::clone),
+        get_subject: matches.get_flag("get-subject"),
+        get_from_email: matches.get_flag("get-from-email"),
+        get_list_id: matches.get_flag("get-list-id"),
+        get_content_type: matches.get_flag("get-content-type"),
+        get_thread_index: matches.get_flag("get-thread-index"),
+        get_spam_score: matches.get_flag("get-spam-score"),
+        get_user_agent: matches.get_flag("get-user-agent"),
+        get_envelope_from: matches.get_flag("get-envelope-from"),
+        get_dmarc: matches.get_flag("get-dmarc"),
+        get_priority: matches.get_flag("get-priority"),
+        get_size: matches.get_flag("get-size"),
+        human: matches.get_flag("human"),
+        get_bounce: matches.get_flag("get-bounce"),
+        mime_tree: matches.get_flag("mime-tree"),
+        check_spoof: matches.get_flag("check-spoof"),
+        color: use_color(matches, std::io::stdout()),
+        color_stderr: use_color(matches, std::io::stderr()),
+        get_content_language: matches.get_flag("get-content-language"),
+        detect_language: matches.get_flag("detect-language"),
+        get_all_addresses: matches.get_flag("get-all-addresses"),
+        list_links: matches.get_flag("list-links"),
+        get_received_chain: matches.get_flag("get-received-chain"),
+        get_raw_header: matches.get_one::<String>("get-raw-header").cloned(),
+        verify_attachments: matches.get_flag("verify-attachments"),
+        attachment_type_summary: matches.get_flag("attachment-type-summary"),
+        get_attachment_names: matches.get_flag("get-attachment-names"),
+        attachment_hashes: matches
+            .get_one::<String>("attachment-hashes")
+            .map(|algorithm| match algorithm.as_str() {
+                "md5" => HashAlgorithm::Md5,
+                _ => HashAlgorithm::Sha256,
+            }),
+        html_preview: matches.get_flag("html-preview"),
+        fragment: matches.get_flag("fragment"),
+        debug_html: matches.get_flag("debug-html"),
+        to_org: matches.get_flag("to-org"),
+        generate_html: matches.get_flag("generate-html") && !matches.get_flag("no-html"),
+        no_html: matches.get_flag("no-html"),
+        passthrough_signed: matches.get_flag("passthrough-signed"),
+        preserve_structure: matches.get_flag("preserve-structure"),
+        rebuild_only: matches.get_flag("rebuild-only"),
+        detox: matches.get_flag("detox"),
+        verify_rebuild: matches.get_flag("verify-rebuild"),
+        add_pixel: matches.get_one::<String>("add-pixel").cloned(),
+        pixel_id: matches.get_one::<String>("pixel-id").cloned(),
+        pixel_fallback: matches.get_one::<String>("pixel-fallback").cloned(),
+        pixel_headers: matches.get_flag("pixel-base-from-headers").then(|| {
+            (
+                matches
+                    .get_one::<String>("pixel-host-header")
+                    .unwrap()
+                    .clone(),
+                matches
+                    .get_one::<String>("pixel-proto-header")
+                    .unwrap()
+                    .clone(),
+            )
+        }),
+        imap: get_imap_options(matches),
+        imap_deadline: matches
+            .get_one::<u64>("imap-deadline")
+            .map(|ms| Duration::from_millis(*ms)),
+        html: get_html_options(matches, body.clone()),
+        rebuild: get_rebuild_options(matches, body),
+    }
+}
+
+fn get_html_options(matches: &clap::ArgMatches, body: BodyOptions) -> HtmlOptions {
+    HtmlOptions {
+        inline_css: matches.get_flag("inline-css"),
+        tables: matches.get_flag("tables"),
+        body,
+        strip_quotes: matches
+            .get_flag("strip-quotes")
+            .then(|| usize::from(matches.get_flag("keep-one-quote-level"))),
+        no_pre_markdown: matches.get_flag("no-pre-markdown"),
+        minify: matches.get_flag("minify-html"),
+        strip_comments: matches.get_flag("strip-html-comments"),
+        max_size: matches.get_one::<usize>("max-html-size").copied(),
+        abort_oversized: matches
+            .get_one::<String>("oversized-html")
+            .map(String::as_str)
+            == Some("abort"),
+        max_body_bytes: matches.get_one::<usize>("max-body-bytes").copied(),
+        detect_tables: matches.get_flag("detect-tables"),
+        emoji: matches.get_flag("emoji"),
+        heading_offset: *matches.get_one::<u8>("heading-offset").unwrap(),
+        break_on: matches.get_one::<Regex>("break-on").cloned(),
+        inline_cid: matches.get_flag("inline-cid"),
+        proxy_images: matches.get_one::<String>("proxy-images").cloned(),
+        linkify: matches.get_flag("linkify"),
+        md_flags: matches
+            .get_many::<String>("md-flags")
+            .map_or_else(Vec::new, |flags| {
+                flags
+                    .filter_map(|flag| MarkdownExtension::from_name(flag))
+                    .collect()
+            }),
+    }
+}
+
+fn get_imap_options(matches: &clap::ArgMatches) -> Option<ImapOptions> {
     match (
         matches.get_one::<String>("put-on-imap"),
         matches.get_one::<String>("server"),
-        matches
-            .get_one::<String>("port")
-            .unwrap_or(&String::from("933"))
-            .parse::<u16>(),
         matches.get_one::<String>("user"),
         matches.get_one::<String>("password"),
-        matches.get_flag("generate-html"),
     ) {
-        (Some(mailbox), Some(server), Ok(port), Some(user), Some(pass), generate_html) => {
-            let mut eml_to_store = eml.clone();
-            if generate_html {
-                eml_to_store = eml_to_store.html_body(text_body_as_html(message, None));
-            };
-            put_email_on_imap_server(eml_to_store, mailbox, server, port, user, pass);
-        }
-        (None, _, _, _, _, _) => (),
-        (_, _, _, _, _, _) => panic!("Missing arguments for put-on-imap"),
+        (Some(mailbox), Some(server), Some(user), Some(pass)) => Some(ImapOptions {
+            mailbox: mailbox.clone(),
+            server: server.clone(),
+            // IMAPS
+            port: matches.get_one::<u16>("port").copied().unwrap_or(993),
+            user: user.clone(),
+            password: pass.clone(),
+            auth: match matches.get_one::<String>("imap-auth").map(String::as_str) {
+                Some("PLAIN") => ImapAuth::Plain,
+                Some("XOAUTH2") => ImapAuth::XOAuth2,
+                _ => ImapAuth::Login,
+            },
+            separator: matches.get_one::<char>("imap-separator").copied(),
+            move_existing: matches.get_one::<String>("imap-move-existing").cloned(),
+        }),
+        (None, _, _, _) => None,
+        (_, _, _, _) => panic!("Missing arguments for put-on-imap"),
     }
 }
 
-fn get_file_name(attachment: &MessagePart) -> String {
-    let mut result = String::new();
-    attachment.headers().iter().for_each(|header| {
-        if header.name == HeaderName::Rfc(RfcHeader::ContentDisposition) {
-            if let HeaderValue::ContentType(content_type) = &header.value {
-                result = content_type.attribute("filename").unwrap().to_owned();
-            };
-        }
-    });
-    result
+fn get_email_paths(path: &String) -> Vec<String> {
+    if path == "-" {
+        return vec![path.clone()];
+    }
+    let mut paths: Vec<String> = if Path::new(path).is_dir() {
+        std::fs::read_dir(path)
+            .expect("Unable to read directory")
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|entry| entry.is_file())
+            .map(|entry| entry.to_string_lossy().into_owned())
+            .collect()
+    } else if path.contains(['*', '?', '[']) {
+        glob::glob(path)
+            .expect("Invalid glob pattern")
+            .filter_map(Result::ok)
+            .filter(|entry| entry.is_file())
+            .map(|entry| entry.to_string_lossy().into_owned())
+            .collect()
+    } else {
+        return vec![path.clone()];
+    };
+    paths.sort();
+    paths
 }
 
-fn get_content_type(attachment: &MessagePart) -> String {
-    let mut result = String::new();
-    attachment.headers().iter().for_each(|header| {
-        if header.name == HeaderName::Rfc(RfcHeader::ContentType) {
-            if let HeaderValue::ContentType(content_type) = &header.value {
-                result.push_str(content_type.ctype().to_owned().as_str());
-                if let Some(subtype) = content_type.subtype() {
-                    result.push('/');
-                    result.push_str(subtype);
-                }
-            };
+fn get_listed_paths(list_path: &str) -> Vec<String> {
+    std::fs::read_to_string(list_path)
+        .expect("Unable to read file list")
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn parse_date_argument(value: &str) -> Result<i64, String> {
+    DateTime::parse_rfc3339(value)
+        .or_else(|| DateTime::parse_rfc3339(&format!("{}T00:00:00Z", value)))
+        .map(|date| date.to_timestamp())
+        .ok_or_else(|| String::from("expected YYYY-MM-DD or RFC 3339 date"))
+}
+
+fn is_within_date_range(message: &Message, options: &Options) -> bool {
+    if options.since.is_none() && options.before.is_none() {
+        return true;
+    }
+    match message.date() {
+        Some(date) => {
+            let timestamp = date.to_timestamp();
+            options.since.is_none_or(|since| timestamp >= since)
+                && options.before.is_none_or(|before| timestamp < before)
         }
-    });
-    result
+        None => options.include_undated,
+    }
 }
 
-fn copy_attachments<'a>(mut dest: MessageBuilder<'a>, source: &'a Message) -> MessageBuilder<'a> {
-    for attachment in source.attachments() {
-        let content_type = get_content_type(attachment);
-        let file_name = get_file_name(attachment);
+fn get_address_list(matches: &clap::ArgMatches, name: &str) -> Vec<String> {
+    let addresses: Vec<String> = matches
+        .get_many::<String>(name)
+        .map_or_else(Vec::new, |values| values.cloned().collect());
+    if let Some(invalid) = addresses
+        .iter()
+        .find(|address| parse_addresses(address).is_none())
+    {
+        eprintln!("Invalid --{} address: {}", name, invalid);
+        std::process::exit(1);
+    }
+    addresses
+}
 
-        match &attachment.body {
-            PartType::Binary(body) => {
-                dest = dest.binary_attachment(content_type, file_name, body.as_ref());
+fn get_rebuild_options(matches: &clap::ArgMatches, body: BodyOptions) -> RebuildOptions {
+    let get_list = |name: &str| {
+        matches
+            .get_many::<String>(name)
+            .map_or_else(Vec::new, |values| values.cloned().collect())
+    };
+    let detox = matches.get_flag("detox");
+    RebuildOptions {
+        redact: matches
+            .get_flag("redact")
+            .then(|| get_list("redact-headers")),
+        to_override: get_address_list(matches, "to-override"),
+        cc_override: get_address_list(matches, "cc-override"),
+        keep_original_html: !matches.get_flag("no-html")
+            && (detox || matches.get_flag("keep-original-html")),
+        strip_html_comments: detox || matches.get_flag("strip-html-comments"),
+        strip_pixels: detox || matches.get_flag("strip-pixels"),
+        proxy_images: matches.get_one::<String>("proxy-images").cloned(),
+        remove_remote_images: detox || matches.get_flag("remove-remote-images"),
+        format_flowed: matches.get_flag("format-flowed"),
+        self_reference: matches.get_flag("self-reference"),
+        new_message_id: matches.get_flag("new-message-id"),
+        body,
+        strip_attachments: matches.get_flag("strip-attachments"),
+        keep_types: get_list("keep-types"),
+        subject_template: matches.get_one::<String>("subject-template").cloned(),
+        dedupe_headers: matches.get_flag("dedupe-headers"),
+        keep_return_path: matches.get_flag("keep-return-path"),
+        drop_sender: matches.get_flag("drop-sender"),
+        attach_original: matches.get_flag("attach-original"),
+        normalize_header_case: matches.get_flag("normalize-header-case"),
+        text_encoding: matches.get_one::<String>("text-encoding").map(|encoding| {
+            match encoding.as_str() {
+                "7bit" => TextEncoding::SevenBit,
+                "quoted-printable" => TextEncoding::QuotedPrintable,
+                _ => TextEncoding::Base64,
             }
-            PartType::Text(body) => {
-                dest = dest.text_attachment(content_type, file_name, body.as_ref());
+        }),
+        dkim: get_dkim_options(matches),
+    }
+}
+
+fn get_dkim_options(matches: &clap::ArgMatches) -> Option<DkimOptions> {
+    let path = matches.get_one::<String>("dkim-key")?;
+    Some(DkimOptions {
+        key: std::fs::read_to_string(path).expect("Unable to read DKIM key"),
+        selector: matches.get_one::<String>("dkim-selector")?.clone(),
+        domain: matches.get_one::<String>("dkim-domain")?.clone(),
+    })
+}
+
+fn get_body_options(matches: &clap::ArgMatches) -> BodyOptions {
+    BodyOptions {
+        charset_detect: matches.get_flag("charset-detect"),
+        force_decode: matches.get_flag("force-decode-body"),
+        text: matches
+            .get_one::<String>("body-file")
+            .map(|path| std::fs::read_to_string(path).expect("Unable to read body file")),
+    }
+}
+
+fn get_email_to_store(
+    eml: &MessageBuilder,
+    message: &Message,
+    options: &Options,
+) -> Option<Vec<u8>> {
+    options.imap.as_ref()?;
+    let mut eml_to_store = eml.clone();
+    if options.generate_html && eml_to_store.html_body.is_none() {
+        let html = text_body_as_html(message, None, &options.html);
+        eml_to_store = eml_to_store.html_body(html_or_exit(html, options));
+    };
+    Some(write_message_or_exit(eml_to_store, &options.rebuild))
+}
+
+fn html_or_exit(html: Result<String, String>, options: &Options) -> String {
+    html.and_then(|html| check_html_size(&html, &options.html).map(|_| html))
+        .unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        })
+}
+
+fn write_message_or_exit(eml: MessageBuilder, options: &RebuildOptions) -> Vec<u8> {
+    write_message(eml, options).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    })
+}
+
+fn handle_put_email_on_imap_server(
+    emails: Vec<(String, Vec<u8>)>,
+    options: &ImapOptions,
+    deadline: Option<Duration>,
+) {
+    if emails.is_empty() {
+        return;
+    }
+    let names: Vec<String> = emails.iter().map(|(name, _)| name.clone()).collect();
+    let results = match deadline {
+        Some(deadline) => put_email_on_imap_server_within(emails, options, deadline),
+        None => put_email_on_imap_server(&emails, options),
+    };
+    let results = match results {
+        Ok(results) => results,
+        // no email was stored when session could not be established
+        Err(error) => {
+            names
+                .iter()
+                .for_each(|name| eprintln!("{}: failed ({})", name, error));
+            std::process::exit(1);
+        }
+    };
+    let is_batch = results.len() > 1;
+    let mut has_failed = false;
+    for (name, result) in results {
+        match result {
+            Ok(()) if is_batch => eprintln!("{}: stored", name),
+            Ok(()) => (),
+            Err(error) => {
+                eprintln!("{}: failed ({})", name, error);
+                has_failed = true;
             }
-            _ => (),
         }
     }
-    dest
+    if has_failed {
+        std::process::exit(1);
+    }
+}
+
+// session runs on its own thread, so it can be abandoned once deadline passes
+fn put_email_on_imap_server_within(
+    emails: Vec<(String, Vec<u8>)>,
+    options: &ImapOptions,
+    deadline: Duration,
+) -> imap::error::Result<Vec<(String, imap::error::Result<()>)>> {
+    let (sender, receiver) = mpsc::channel();
+    let options = options.clone();
+    std::thread::spawn(move || {
+        let _ = sender.send(put_email_on_imap_server(&emails, &options));
+    });
+    match receiver.recv_timeout(deadline) {
+        Ok(results) => results,
+        Err(RecvTimeoutError::Timeout) => {
+            eprintln!(
+                "IMAP deadline of {}ms exceeded, append may not have completed",
+                deadline.as_millis()
+            );
+            std::process::exit(1);
+        }
+        // session thread panicked, its message is already printed
+        Err(RecvTimeoutError::Disconnected) => std::process::exit(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn imap_matches(port: Option<&str>) -> Result<clap::ArgMatches, clap::Error> {
+        let mut args = vec![
+            "enrichmail",
+            "--put-on-imap",
+            "INBOX",
+            "--server",
+            "imap.example.com",
+            "--user",
+            "john",
+            "--password",
+            "secret",
+        ];
+        if let Some(port) = port {
+            args.extend(["--port", port]);
+        }
+        args.push("mail.eml");
+        cli().try_get_matches_from(args)
+    }
+
+    #[test]
+    fn imap_port_defaults_to_imaps_when_omitted() {
+        let imap = get_imap_options(&imap_matches(None).unwrap()).unwrap();
+        assert_eq!(imap.port, 993);
+    }
+
+    #[test]
+    fn imap_port_is_taken_from_argument() {
+        let imap = get_imap_options(&imap_matches(Some("143")).unwrap()).unwrap();
+        assert_eq!(imap.port, 143);
+    }
+
+    #[test]
+    fn invalid_imap_port_is_an_error() {
+        assert!(imap_matches(Some("imaps")).is_err());
+    }
 }
@@ -0,0 +1,114 @@
+//! Markdown to org-mode conversion for message export.
+
+use comrak::nodes::{AstNode, ListType, NodeValue};
+use comrak::{parse_document, Arena, ComrakOptions};
+
+/// Converts markdown to org syntax, headings are nested below `level` stars.
+pub fn markdown_to_org(markdown: &str, level: usize) -> String {
+    let arena = Arena::new();
+    let mut options = ComrakOptions::default();
+    options.extension.strikethrough = true;
+    options.extension.table = true;
+    let root = parse_document(&arena, markdown, &options);
+    children_blocks(root, level, "\n")
+}
+
+fn children_blocks<'a>(node: &'a AstNode<'a>, level: usize, separator: &str) -> String {
+    node.children()
+        .map(|child| block(child, level))
+        .filter(|block| !block.is_empty())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn block<'a>(node: &'a AstNode<'a>, level: usize) -> String {
+    match &node.data.borrow().value {
+        NodeValue::Paragraph => format!("{}\n", inlines(node)),
+        NodeValue::Heading(heading) => format!(
+            "{} {}\n",
+            "*".repeat(level + heading.level as usize),
+            inlines(node)
+        ),
+        NodeValue::CodeBlock(code) => match code.info.split_whitespace().next() {
+            Some(language) => format!("#+begin_src {}\n{}#+end_src\n", language, code.literal),
+            None => format!("#+begin_example\n{}#+end_example\n", code.literal),
+        },
+        NodeValue::HtmlBlock(html) => {
+            format!("#+begin_export html\n{}#+end_export\n", html.literal)
+        }
+        NodeValue::BlockQuote => format!(
+            "#+begin_quote\n{}#+end_quote\n",
+            children_blocks(node, level, "\n")
+        ),
+        NodeValue::ThematicBreak => "-----\n".to_owned(),
+        NodeValue::List(list) => node
+            .children()
+            .enumerate()
+            .map(|(index, item)| {
+                let bullet = match list.list_type {
+                    ListType::Bullet => "- ".to_owned(),
+                    ListType::Ordered => format!("{}. ", list.start + index),
+                };
+                list_item(item, level, &bullet)
+            })
+            .collect(),
+        NodeValue::Table(_) => node.children().enumerate().map(table_row).collect(),
+        _ => children_blocks(node, level, "\n"),
+    }
+}
+
+fn list_item<'a>(item: &'a AstNode<'a>, level: usize, bullet: &str) -> String {
+    let content = children_blocks(item, level, "");
+    let indent = " ".repeat(bullet.len());
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| match index {
+            0 => format!("{}{}\n", bullet, line),
+            _ if line.is_empty() => "\n".to_owned(),
+            _ => format!("{}{}\n", indent, line),
+        })
+        .collect()
+}
+
+fn table_row<'a>((index, row): (usize, &'a AstNode<'a>)) -> String {
+    let cells: Vec<String> = row.children().map(inlines).collect();
+    let line = format!("| {} |\n", cells.join(" | "));
+    match index {
+        0 => format!(
+            "{}|{}|\n",
+            line,
+            cells
+                .iter()
+                .map(|cell| "-".repeat(cell.chars().count() + 2))
+                .collect::<Vec<_>>()
+                .join("+")
+        ),
+        _ => line,
+    }
+}
+
+fn inlines<'a>(node: &'a AstNode<'a>) -> String {
+    node.children().map(inline).collect()
+}
+
+fn inline<'a>(node: &'a AstNode<'a>) -> String {
+    match &node.data.borrow().value {
+        NodeValue::Text(text) => text.clone(),
+        NodeValue::SoftBreak => "\n".to_owned(),
+        NodeValue::LineBreak => "\\\\\n".to_owned(),
+        NodeValue::Code(code) => format!("~{}~", code.literal),
+        NodeValue::HtmlInline(html) => html.clone(),
+        NodeValue::Emph => format!("/{}/", inlines(node)),
+        NodeValue::Strong => format!("*{}*", inlines(node)),
+        NodeValue::Strikethrough => format!("+{}+", inlines(node)),
+        NodeValue::Link(link) => match inlines(node) {
+            description if description.is_empty() || description == link.url => {
+                format!("[[{}]]", link.url)
+            }
+            description => format!("[[{}][{}]]", link.url, description),
+        },
+        NodeValue::Image(link) => format!("[[{}]]", link.url),
+        _ => inlines(node),
+    }
+}
@@ -0,0 +1,167 @@
+use enrichmail::{
+    get_attachment_hash, get_attachment_type_summary, get_builder_from_parser, get_file_name,
+    write_message, HashAlgorithm, RebuildOptions,
+};
+use mail_parser::{Message, MimeHeaders};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+mod common;
+use common::{fixture, rebuild};
+
+#[test]
+fn strip_attachments_drops_all_attachment_parts() {
+    let output = rebuild(
+        "attachment.eml",
+        &RebuildOptions {
+            strip_attachments: true,
+            ..RebuildOptions::default()
+        },
+    );
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert_eq!(rebuilt.attachment_count(), 0);
+    assert_eq!(rebuilt.subject(), Some("Report"));
+    assert_eq!(rebuilt.body_text(0).unwrap().trim_end(), "Report attached.");
+}
+
+#[test]
+fn keep_types_filters_attachments_by_content_type() {
+    let output = rebuild(
+        "mixed.eml",
+        &RebuildOptions {
+            keep_types: vec![String::from("image/*")],
+            ..RebuildOptions::default()
+        },
+    );
+    let rebuilt = Message::parse(&output).unwrap();
+
+    let names: Vec<String> = rebuilt.attachments().map(get_file_name).collect();
+    assert_eq!(names, vec!["photo.png", "photo.jpg"]);
+}
+
+#[test]
+fn keep_types_matches_exact_content_type() {
+    let output = rebuild(
+        "mixed.eml",
+        &RebuildOptions {
+            keep_types: vec![String::from("application/pdf"), String::from("text/csv")],
+            ..RebuildOptions::default()
+        },
+    );
+    let rebuilt = Message::parse(&output).unwrap();
+
+    let names: Vec<String> = rebuilt.attachments().map(get_file_name).collect();
+    assert_eq!(names, vec!["scan.pdf", "data.csv"]);
+}
+
+#[test]
+fn inline_images_keep_content_id() {
+    let output = rebuild("inline.eml", &RebuildOptions::default());
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert_eq!(rebuilt.attachment_count(), 1);
+    let image = rebuilt.attachment(0).unwrap();
+    assert_eq!(image.content_id(), Some("logo@example.com"));
+    assert_eq!(image.contents(), b"\x89PNG\r\n\x1a\n");
+    assert!(!image.content_disposition().unwrap().is_attachment());
+}
+
+#[test]
+fn attachment_hash_uses_decoded_content() {
+    let content = fixture("attachment.eml");
+    let message = Message::parse(&content).unwrap();
+    let attachment = message.attachment(0).unwrap();
+
+    assert_eq!(
+        get_attachment_hash(attachment, HashAlgorithm::Md5),
+        "e6e8aac5bb36e11045b5f1a1db1cb3ef"
+    );
+    assert_eq!(
+        get_attachment_hash(attachment, HashAlgorithm::Sha256),
+        "237bac67a518e2dfc901ecbf060f59cde465d4644cf9d0783a877a321f9bbcb3"
+    );
+}
+
+#[test]
+fn attachment_type_summary_sorts_by_count() {
+    let content = fixture("mixed.eml");
+    let message = Message::parse(&content).unwrap();
+
+    assert_eq!(
+        get_attachment_type_summary(&message),
+        vec![
+            (String::from("application/pdf"), 1),
+            (String::from("image/jpeg"), 1),
+            (String::from("image/png"), 1),
+            (String::from("text/csv"), 1),
+        ]
+    );
+}
+
+#[test]
+fn attach_original_keeps_input_bytes() {
+    let content = fixture("attachment.eml");
+    let message = Message::parse(&content).unwrap();
+    let options = RebuildOptions {
+        attach_original: true,
+        ..RebuildOptions::default()
+    };
+    let output = write_message(get_builder_from_parser(&message, &options), &options).unwrap();
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert_eq!(rebuilt.attachment_count(), 2);
+    let original = rebuilt.attachment(1).unwrap();
+    assert_eq!(get_file_name(original), "original.eml");
+    assert_eq!(
+        original.message().unwrap().raw_message(),
+        content.as_slice()
+    );
+    // message/rfc822 may only use identity encodings
+    let text = String::from_utf8_lossy(&output);
+    let part_headers = &text[text.find("Content-Type: message/rfc822").unwrap()..];
+    let part_headers = &part_headers[..part_headers.find("\r\n\r\n").unwrap()];
+    assert!(part_headers.contains("Content-Transfer-Encoding: 7bit"));
+    assert!(text.contains("\r\nSubject: Report\r\n"));
+}
+
+#[test]
+fn non_ascii_file_name_is_rfc2231_encoded() {
+    let output = rebuild("polish_attachment.eml", &RebuildOptions::default());
+    let rebuilt = Message::parse(&output).unwrap();
+    let text = String::from_utf8_lossy(&output);
+
+    assert!(text.contains("filename*0*=utf-8''Sprawozdanie%20roczne"));
+    assert!(!text.contains("=?utf-8?"));
+    let long_lines: Vec<_> = text.lines().filter(|line| line.len() > 78).collect();
+    assert!(
+        long_lines.is_empty(),
+        "lines over 78 chars: {:?}",
+        long_lines
+    );
+    assert_eq!(
+        get_file_name(rebuilt.attachment(0).unwrap()),
+        "Sprawozdanie roczne zażółć gęślą jaźń.pdf"
+    );
+}
+
+#[test]
+fn binary_attachment_piped_through_stdin_is_byte_identical() {
+    let content = fixture("binary_attachment.eml");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_enrichmail"))
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&content).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let original = Message::parse(&content).unwrap();
+    let rebuilt = Message::parse(&output.stdout).unwrap();
+    let blob = rebuilt.attachment(0).unwrap();
+    assert_eq!(get_file_name(blob), "blob.bin");
+    assert_eq!(blob.contents(), original.attachment(0).unwrap().contents());
+    assert_eq!(blob.contents().len(), 1024);
+}
@@ -0,0 +1,15 @@
+use enrichmail::{get_builder_from_parser, write_message, RebuildOptions};
+use mail_parser::Message;
+
+pub fn fixture(name: &str) -> Vec<u8> {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    std::fs::read(&path).unwrap_or_else(|_| panic!("Missing fixture: {}", path))
+}
+
+// not every test crate rebuilds messages
+#[allow(dead_code)]
+pub fn rebuild(name: &str, options: &RebuildOptions) -> Vec<u8> {
+    let content = fixture(name);
+    let message = Message::parse(&content).unwrap();
+    write_message(get_builder_from_parser(&message, options), options).unwrap()
+}
@@ -0,0 +1,404 @@
+use enrichmail::{
+    get_builder_from_parser, get_builder_message_id, get_email_headers, get_message_content_type,
+    get_received_chain, get_thread_index, is_signed_or_encrypted, msg_to_eml, verify_rebuild,
+    write_message, DkimOptions, RebuildOptions,
+};
+use mail_parser::{HeaderValue, Message};
+
+mod common;
+use common::{fixture, rebuild};
+
+#[test]
+fn received_chain_keeps_order_and_unfolds() {
+    let content = fixture("received.eml");
+    let message = Message::parse(&content).unwrap();
+
+    assert_eq!(
+        get_received_chain(&message),
+        vec![
+            "from relay.example.net (relay.example.net [192.0.2.10]) by mx.example.com with ESMTPS; Tue, 2 May 2023 10:00:05 +0000",
+            "from laptop.example.org by relay.example.net; Tue, 2 May 2023 10:00:01 +0000",
+        ]
+    );
+}
+
+#[test]
+fn received_headers_stay_on_top_in_order() {
+    let output = rebuild("received_order.eml", &RebuildOptions::default());
+    let rebuilt = Message::parse(&output).unwrap();
+    let names: Vec<_> = rebuilt
+        .headers_raw()
+        .map(|(name, _)| name)
+        .take(4)
+        .collect();
+
+    assert_eq!(names, ["Received", "Received", "Received", "X-Spam-Score"]);
+    assert_eq!(
+        get_received_chain(&rebuilt),
+        vec![
+            "from mx.example.com by imap.example.com; Tue, 2 May 2023 10:00:09 +0000",
+            "from relay.example.net by mx.example.com; Tue, 2 May 2023 10:00:05 +0000",
+            "from laptop.example.org by relay.example.net; Tue, 2 May 2023 10:00:01 +0000",
+        ]
+    );
+}
+
+#[test]
+fn subject_template_rewrites_decoded_subject() {
+    let output = rebuild(
+        "encoded_subject.eml",
+        &RebuildOptions {
+            subject_template: Some(String::from("[ENRICHED] {subject}")),
+            ..RebuildOptions::default()
+        },
+    );
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert_eq!(rebuilt.subject(), Some("[ENRICHED] Zażółć report"));
+}
+
+#[test]
+fn subject_template_adds_missing_subject() {
+    let content = fixture("no_subject.eml");
+    let message = Message::parse(&content).unwrap();
+    let options = RebuildOptions {
+        subject_template: Some(String::from("[E] {subject}")),
+        ..RebuildOptions::default()
+    };
+    let output = rebuild("no_subject.eml", &options);
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert_eq!(rebuilt.subject(), Some("[E] "));
+    assert!(verify_rebuild(&message, &output, &options).is_empty());
+}
+
+#[test]
+fn subject_is_copied_without_template() {
+    let output = rebuild("encoded_subject.eml", &RebuildOptions::default());
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert_eq!(rebuilt.subject(), Some("Zażółć report"));
+}
+
+#[test]
+fn addresses_without_email_do_not_panic() {
+    let output = rebuild("malformed_address.eml", &RebuildOptions::default());
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert_eq!(rebuilt.subject(), Some("Broken addresses"));
+    match rebuilt.to() {
+        HeaderValue::Address(to) => assert_eq!(to.address.as_deref(), Some("bob@example.com")),
+        other => panic!("Unexpected To header: {:?}", other),
+    }
+}
+
+#[test]
+fn dedupe_headers_keeps_first_singleton() {
+    let options = RebuildOptions {
+        dedupe_headers: true,
+        ..RebuildOptions::default()
+    };
+    let output = String::from_utf8(rebuild("doubled_subject.eml", &options)).unwrap();
+
+    assert_eq!(output.matches("Subject: ").count(), 1);
+    assert!(output.contains("Subject: First subject\r\n"));
+    assert_eq!(output.matches("Received: ").count(), 2);
+}
+
+#[test]
+fn duplicate_headers_are_copied_without_dedupe() {
+    let output =
+        String::from_utf8(rebuild("doubled_subject.eml", &RebuildOptions::default())).unwrap();
+
+    assert_eq!(output.matches("Subject: ").count(), 2);
+}
+
+#[test]
+fn return_path_is_dropped_and_sender_kept_by_default() {
+    let output = String::from_utf8(rebuild("return_path.eml", &RebuildOptions::default())).unwrap();
+
+    assert!(!output.contains("Return-Path: "));
+    assert_eq!(output.matches("Sender: ").count(), 1);
+}
+
+#[test]
+fn return_path_is_not_duplicated_when_kept() {
+    let options = RebuildOptions {
+        keep_return_path: true,
+        drop_sender: true,
+        ..RebuildOptions::default()
+    };
+    let output = String::from_utf8(rebuild("return_path.eml", &options)).unwrap();
+
+    assert_eq!(output.matches("Return-Path: ").count(), 1);
+    assert!(!output.contains("Sender: "));
+}
+
+#[test]
+fn email_headers_stop_at_body() {
+    let path = format!(
+        "{}/tests/fixtures/attachment.eml",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let headers = get_email_headers(&path).unwrap();
+
+    assert!(headers.ends_with(b"boundary=\"BOUNDARY\"\r\n\r\n"));
+    let message = Message::parse(&headers).unwrap();
+    assert_eq!(message.subject(), Some("Report"));
+    assert_eq!(message.message_id(), Some("attachment@example.com"));
+}
+
+#[test]
+fn message_content_type_is_top_level() {
+    let content = fixture("attachment.eml");
+    let message = Message::parse(&content).unwrap();
+    assert_eq!(get_message_content_type(&message), "multipart/mixed");
+
+    let message = Message::parse(b"Subject: No type\r\n\r\nHello.\r\n").unwrap();
+    assert_eq!(get_message_content_type(&message), "text/plain");
+}
+
+#[test]
+fn long_headers_are_folded_within_limits() {
+    let content = fixture("long_references.eml");
+    let message = Message::parse(&content).unwrap();
+    let output =
+        String::from_utf8(rebuild("long_references.eml", &RebuildOptions::default())).unwrap();
+    let (headers, _) = output.split_once("\r\n\r\n").unwrap();
+
+    for line in headers.split("\r\n") {
+        assert!(line.len() <= 78, "line too long: {}", line);
+    }
+    let rebuilt = Message::parse(output.as_bytes()).unwrap();
+    assert_eq!(rebuilt.references(), message.references());
+    assert_eq!(rebuilt.references().as_text_list().unwrap().len(), 30);
+}
+
+#[test]
+fn long_text_headers_are_folded_within_limits() {
+    let content = fixture("long_text_headers.eml");
+    let message = Message::parse(&content).unwrap();
+    let output =
+        String::from_utf8(rebuild("long_text_headers.eml", &RebuildOptions::default())).unwrap();
+    let (headers, _) = output.split_once("\r\n\r\n").unwrap();
+
+    for line in headers.split("\r\n") {
+        assert!(line.len() <= 78, "line too long: {}", line);
+        assert!(!line.trim().is_empty(), "blank folded line");
+    }
+    let rebuilt = Message::parse(output.as_bytes()).unwrap();
+    assert_eq!(rebuilt.subject(), message.subject());
+    assert_eq!(
+        rebuilt
+            .header_raw("X-Campaign-Description")
+            .map(str::split_whitespace)
+            .map(|words| words.collect::<Vec<_>>()),
+        message
+            .header_raw("X-Campaign-Description")
+            .map(str::split_whitespace)
+            .map(|words| words.collect::<Vec<_>>())
+    );
+}
+
+#[test]
+fn signed_messages_are_detected() {
+    let content = fixture("signed.eml");
+    assert!(is_signed_or_encrypted(&Message::parse(&content).unwrap()));
+
+    let content = fixture("attachment.eml");
+    assert!(!is_signed_or_encrypted(&Message::parse(&content).unwrap()));
+}
+
+#[test]
+fn thread_index_guid_is_decoded() {
+    let raw =
+        b"Thread-Index: AQIDBAUGEBESExQVFhcYGRobHB0eHwkJCQkJ\r\nSubject: Outlook\r\n\r\nHi\r\n";
+    let message = Message::parse(raw).unwrap();
+    assert_eq!(
+        get_thread_index(&message).as_deref(),
+        Some("101112131415161718191a1b1c1d1e1f")
+    );
+
+    let content = fixture("plain.eml");
+    assert_eq!(get_thread_index(&Message::parse(&content).unwrap()), None);
+}
+
+#[test]
+fn normalize_header_case_uses_canonical_names() {
+    let options = RebuildOptions {
+        normalize_header_case: true,
+        ..RebuildOptions::default()
+    };
+    let output = String::from_utf8(rebuild("lowercase_headers.eml", &options)).unwrap();
+
+    assert!(output.contains("\r\nMessage-ID: "));
+    assert!(output.contains("\r\nDKIM-Signature: v=1; a=rsa-sha256; d=example.com\r\n"));
+    assert!(output.contains("\r\nX-Custom-Header: value\r\n"));
+    assert!(!output.contains("message-id"));
+}
+
+#[test]
+fn rebuild_keeps_original_message_id() {
+    let output = rebuild("plain.eml", &RebuildOptions::default());
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert_eq!(rebuilt.message_id(), Some("plain@example.com"));
+    assert!(String::from_utf8_lossy(&output).contains("\r\nMessage-ID: <plain@example.com>\r\n"));
+}
+
+#[test]
+fn new_message_id_replaces_original() {
+    let options = RebuildOptions {
+        new_message_id: true,
+        ..RebuildOptions::default()
+    };
+    let output = rebuild("plain.eml", &options);
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert!(rebuilt.message_id().is_some());
+    assert_ne!(rebuilt.message_id(), Some("plain@example.com"));
+}
+
+#[test]
+fn to_override_parses_name_and_keeps_position() {
+    let options = RebuildOptions {
+        to_override: vec!["Carol <carol@example.com>".to_string()],
+        ..RebuildOptions::default()
+    };
+    let output = String::from_utf8(rebuild("plain.eml", &options)).unwrap();
+
+    assert!(output.contains("\r\nTo: \"Carol\" <carol@example.com>\r\nSubject: "));
+}
+
+#[test]
+fn group_and_empty_address_headers_are_copied() {
+    let output =
+        String::from_utf8(rebuild("group_recipients.eml", &RebuildOptions::default())).unwrap();
+    let (headers, _) = output.split_once("\r\n\r\n").unwrap();
+
+    assert!(headers.contains("\r\nTo: undisclosed-recipients:;\r\n"));
+    assert!(!headers.contains("\r\nCc:"));
+    let rebuilt = Message::parse(output.as_bytes()).unwrap();
+    let HeaderValue::Group(to) = rebuilt.to() else {
+        panic!("To is not a group: {:?}", rebuilt.to());
+    };
+    assert_eq!(to.name.as_deref(), Some("undisclosed-recipients"));
+    assert!(to.addresses.is_empty());
+    let HeaderValue::GroupList(reply_to) = rebuilt.reply_to() else {
+        panic!("Reply-To is not a group list: {:?}", rebuilt.reply_to());
+    };
+    let groups: Vec<_> = reply_to
+        .iter()
+        .map(|group| {
+            let members: Vec<_> = group
+                .addresses
+                .iter()
+                .map(|address| address.address.as_deref().unwrap())
+                .collect();
+            (group.name.as_deref().unwrap(), members)
+        })
+        .collect();
+    assert_eq!(
+        groups,
+        vec![
+            ("Team", vec!["bob@example.com", "carol@example.com"]),
+            ("Other", vec!["dave@example.com"]),
+        ]
+    );
+}
+
+#[test]
+fn new_message_id_is_same_in_every_written_copy() {
+    let content = fixture("plain.eml");
+    let message = Message::parse(&content).unwrap();
+    let options = RebuildOptions {
+        new_message_id: true,
+        self_reference: true,
+        ..RebuildOptions::default()
+    };
+    let eml = get_builder_from_parser(&message, &options);
+    let printed = eml.clone().write_to_vec().unwrap();
+    let stored = eml.write_to_vec().unwrap();
+    let printed = Message::parse(&printed).unwrap();
+    let stored = Message::parse(&stored).unwrap();
+
+    assert_eq!(printed.message_id(), stored.message_id());
+    assert_eq!(printed.references().as_text_ref(), printed.message_id());
+}
+
+#[test]
+fn dkim_signature_is_added_on_top() {
+    let options = RebuildOptions {
+        dkim: Some(DkimOptions {
+            key: String::from_utf8(fixture("dkim.pem")).unwrap(),
+            selector: "mail".to_string(),
+            domain: "example.com".to_string(),
+        }),
+        ..RebuildOptions::default()
+    };
+    let output = String::from_utf8(rebuild("plain.eml", &options)).unwrap();
+
+    assert!(output.starts_with("DKIM-Signature: v=1; a=rsa-sha256; s=mail; d=example.com;"));
+    let rebuilt = Message::parse(output.as_bytes()).unwrap();
+    assert_eq!(rebuilt.message_id(), Some("plain@example.com"));
+}
+
+#[test]
+fn dkim_signing_rejects_invalid_key() {
+    let options = RebuildOptions {
+        dkim: Some(DkimOptions {
+            key: "not a key".to_string(),
+            selector: "mail".to_string(),
+            domain: "example.com".to_string(),
+        }),
+        ..RebuildOptions::default()
+    };
+    let content = fixture("plain.eml");
+    let message = Message::parse(&content).unwrap();
+
+    assert!(write_message(get_builder_from_parser(&message, &options), &options).is_err());
+}
+
+#[test]
+fn msg_file_is_converted_to_email() {
+    let output = msg_to_eml(&fixture("outlook.msg")).unwrap();
+    let message = Message::parse(&output).unwrap();
+
+    assert_eq!(message.subject(), Some("creating an outlook message file"));
+    assert_eq!(
+        message.message_id(),
+        Some("c58b1b52f61f4789ba40339c6e993440")
+    );
+    assert_eq!(
+        message.body_text(0).as_deref(),
+        Some("This message is created by Aspose.Email")
+    );
+    let rebuilt = String::from_utf8(
+        write_message(
+            get_builder_from_parser(&message, &RebuildOptions::default()),
+            &RebuildOptions::default(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(rebuilt.contains("\r\nFrom: <from@domain.com>\r\nTo: <to@domain.com>\r\n"));
+    assert_eq!(rebuilt.matches("Content-Transfer-Encoding").count(), 1);
+}
+
+#[test]
+fn invalid_msg_file_is_rejected() {
+    assert!(msg_to_eml(&fixture("plain.eml")).is_err());
+}
+
+#[test]
+fn builder_message_id_is_generated_when_missing() {
+    let content = fixture("no_message_id.eml");
+    let message = Message::parse(&content).unwrap();
+    let eml = get_builder_from_parser(&message, &RebuildOptions::default());
+    let id = get_builder_message_id(&eml).unwrap().to_owned();
+    let output = eml.write_to_vec().unwrap();
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert!(id.ends_with("@localhost"));
+    assert_eq!(rebuilt.message_id(), Some(id.as_str()));
+}
@@ -0,0 +1,483 @@
+use enrichmail::{
+    check_html_size, detect_language, get_builder_from_parser, get_file_name, get_front_matter,
+    get_message_content_type, get_pixel_base_url, get_pixel_element, pre_markdown, strip_quotes,
+    text_body, text_body_as_html, text_body_as_html_fragment, write_message, BodyOptions,
+    FrontMatter, HtmlOptions, MarkdownExtension, RebuildOptions, TextEncoding,
+};
+use mail_parser::{HeaderValue, Message};
+
+mod common;
+use common::fixture;
+
+fn html_for(name: &str) -> String {
+    let content = fixture(name);
+    let message = Message::parse(&content).unwrap();
+    text_body_as_html(&message, None, &HtmlOptions::default()).unwrap()
+}
+
+#[test]
+fn pre_markdown_forces_line_breaks() {
+    assert_eq!(pre_markdown("one\ntwo", false, None), "one  \ntwo  \n");
+}
+
+#[test]
+fn pre_markdown_keeps_table_rows_with_tables() {
+    assert_eq!(
+        pre_markdown("| a |\n|---|\ntext", true, None),
+        "| a |\n|---|\ntext  \n"
+    );
+}
+
+#[test]
+fn plain_paragraph() {
+    let html = html_for("plain.eml");
+    assert!(html.contains("<p>Hello <em>world</em>, this is a paragraph.</p>"));
+}
+
+#[test]
+fn no_pre_markdown_keeps_soft_line_breaks() {
+    let raw = b"From: a@example.com\r\nSubject: Raw\r\n\r\nfirst line\r\nsecond line\r\n";
+    let message = Message::parse(raw).unwrap();
+
+    let html = text_body_as_html(&message, None, &HtmlOptions::default()).unwrap();
+    assert!(html.contains("first line<br />"));
+
+    let options = HtmlOptions {
+        no_pre_markdown: true,
+        ..HtmlOptions::default()
+    };
+    let html = text_body_as_html(&message, None, &options).unwrap();
+    assert!(html.contains("<p>first line\nsecond line</p>"));
+}
+
+#[test]
+fn code_block() {
+    let html = html_for("code.eml");
+    assert!(html.contains("<p>Run this:</p>"));
+    assert!(html.contains("<pre><code>cargo build --release"));
+}
+
+#[test]
+fn blockquote() {
+    let html = html_for("quote.eml");
+    assert!(html.contains("<p>Sounds good.</p>"));
+    assert!(html.contains("<blockquote>\n<p>Hello world, this is a paragraph.</p>\n</blockquote>"));
+}
+
+#[test]
+fn pixel_injection() {
+    let content = fixture("plain.eml");
+    let message = Message::parse(&content).unwrap();
+    let pixel = get_pixel_element(
+        &["https://track.example.com"],
+        message.message_id().unwrap(),
+    );
+    assert!(pixel.contains(r#"src="https://track.example.com/image/cGxhaW5AZXhhbXBsZS5jb20.gif""#));
+
+    let html = text_body_as_html(&message, Some(pixel.clone()), &HtmlOptions::default()).unwrap();
+    assert!(html.contains(&pixel));
+    assert!(html.find("<em>world</em>").unwrap() < html.find("Open pixel").unwrap());
+}
+
+#[test]
+fn round_trip_through_builder() {
+    let content = fixture("plain.eml");
+    let message = Message::parse(&content).unwrap();
+    let output = get_builder_from_parser(&message, &RebuildOptions::default())
+        .write_to_vec()
+        .unwrap();
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert_eq!(rebuilt.subject(), message.subject());
+    assert_eq!(rebuilt.message_id(), message.message_id());
+    assert_eq!(rebuilt.date(), message.date());
+    match rebuilt.from() {
+        HeaderValue::Address(from) => {
+            assert_eq!(from.address.as_deref(), Some("alice@example.com"));
+        }
+        other => panic!("Unexpected From header: {:?}", other),
+    }
+    assert_eq!(
+        rebuilt.body_text(0).unwrap().trim_end(),
+        "Hello *world*, this is a paragraph."
+    );
+}
+
+#[test]
+fn text_encoding_sets_transfer_encoding() {
+    let content = fixture("attachment.eml");
+    let message = Message::parse(&content).unwrap();
+    let options = RebuildOptions {
+        text_encoding: Some(TextEncoding::QuotedPrintable),
+        ..RebuildOptions::default()
+    };
+    let output = write_message(get_builder_from_parser(&message, &options), &options).unwrap();
+    let rebuilt = Message::parse(&output).unwrap();
+
+    let text = String::from_utf8_lossy(&output);
+    assert!(text.contains("Content-Transfer-Encoding: quoted-printable\r\n\r\nReport attached."));
+    assert_eq!(rebuilt.body_text(0).unwrap().trim_end(), "Report attached.");
+    assert_eq!(rebuilt.attachment(0).unwrap().contents(), b"some notes");
+}
+
+#[test]
+fn seven_bit_text_encoding_rejects_non_ascii_body() {
+    let content = fixture("polish_attachment.eml");
+    let message = Message::parse(&content).unwrap();
+    let options = RebuildOptions {
+        text_encoding: Some(TextEncoding::SevenBit),
+        ..RebuildOptions::default()
+    };
+    let result = write_message(get_builder_from_parser(&message, &options), &options);
+
+    assert_eq!(result, Err("Text body can not be sent as 7bit".to_owned()));
+}
+
+#[test]
+fn round_trip_keeps_attachments() {
+    let content = fixture("attachment.eml");
+    let message = Message::parse(&content).unwrap();
+    let output = get_builder_from_parser(&message, &RebuildOptions::default())
+        .write_to_vec()
+        .unwrap();
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert_eq!(rebuilt.attachment_count(), 1);
+    let attachment = rebuilt.attachment(0).unwrap();
+    assert_eq!(get_file_name(attachment), "notes.txt");
+    assert_eq!(attachment.contents(), b"some notes");
+    assert_eq!(rebuilt.body_text(0).unwrap().trim_end(), "Report attached.");
+}
+
+#[test]
+fn minify_keeps_code_block_contents() {
+    let raw = b"From: a@example.com\r\nSubject: Min\r\n\r\nRun:\r\n\r\n```\r\nfn main() {\r\n    println!();\r\n}\r\n```\r\n";
+    let message = Message::parse(raw).unwrap();
+    let html = text_body_as_html(&message, None, &HtmlOptions::default()).unwrap();
+    let minified = text_body_as_html(
+        &message,
+        None,
+        &HtmlOptions {
+            minify: true,
+            ..HtmlOptions::default()
+        },
+    )
+    .unwrap();
+
+    assert!(minified.len() < html.len());
+    assert!(!minified.contains("\n        "));
+    assert!(minified.starts_with("<html><head>"));
+    assert!(minified.contains("<pre><code>fn main() {  \n    println!();  \n}  \n</code></pre>"));
+}
+
+#[test]
+fn pixel_base_url_from_headers() {
+    let raw = b"X-Forwarded-Host: track.example.com\r\nX-Forwarded-Proto: http\r\nMessage-ID: <px@example.com>\r\n\r\nHi\r\n";
+    let message = Message::parse(raw).unwrap();
+    assert_eq!(
+        get_pixel_base_url(&message, "x-forwarded-host", "x-forwarded-proto").as_deref(),
+        Some("http://track.example.com")
+    );
+    assert_eq!(
+        get_pixel_base_url(&message, "x-forwarded-host", "x-scheme").as_deref(),
+        Some("https://track.example.com")
+    );
+    assert_eq!(
+        get_pixel_base_url(&message, "x-host", "x-forwarded-proto"),
+        None
+    );
+}
+
+#[test]
+fn front_matter_is_parsed_and_stripped() {
+    let content = fixture("front_matter.eml");
+    let message = Message::parse(&content).unwrap();
+
+    assert_eq!(
+        get_front_matter(&message, &BodyOptions::default()),
+        FrontMatter {
+            pixel_url: Some(String::from("https://track.example.com")),
+            css: Some(String::from("p { color: red; }")),
+            subject_prefix: Some(String::from("[ANN]")),
+        }
+    );
+    assert_eq!(
+        text_body(&message, &BodyOptions::default()).trim_end(),
+        "We *launched*."
+    );
+
+    let html = text_body_as_html(&message, None, &HtmlOptions::default()).unwrap();
+    assert!(html.contains("p { color: red; }"));
+    assert!(!html.contains("pixel_url"));
+
+    let output = get_builder_from_parser(&message, &RebuildOptions::default())
+        .write_to_string()
+        .unwrap();
+    assert!(output.contains("Subject: [ANN] Launch\r\n"));
+    assert!(!output.contains("subject_prefix"));
+}
+
+#[test]
+fn text_without_front_matter_is_unchanged() {
+    let content = fixture("plain.eml");
+    let message = Message::parse(&content).unwrap();
+
+    assert_eq!(
+        get_front_matter(&message, &BodyOptions::default()),
+        FrontMatter::default()
+    );
+    assert_eq!(
+        text_body(&message, &BodyOptions::default()).trim_end(),
+        "Hello *world*, this is a paragraph."
+    );
+}
+
+#[test]
+fn horizontal_rules_are_not_front_matter() {
+    let content = fixture("horizontal_rules.eml");
+    let message = Message::parse(&content).unwrap();
+
+    assert_eq!(
+        get_front_matter(&message, &BodyOptions::default()),
+        FrontMatter::default()
+    );
+    assert_eq!(
+        text_body(&message, &BodyOptions::default()).trim_end(),
+        "---\nImportant paragraph the reader must see.\n---\nRest"
+    );
+}
+
+#[test]
+fn fragment_has_no_document_wrapper() {
+    let content = fixture("plain.eml");
+    let message = Message::parse(&content).unwrap();
+
+    assert_eq!(
+        text_body_as_html_fragment(&message, &HtmlOptions::default()),
+        "<p>Hello <em>world</em>, this is a paragraph.</p>\n"
+    );
+}
+
+#[test]
+fn linkify_wraps_bare_urls() {
+    let raw = b"Subject: Links\r\n\r\nSee https://example.com/docs. Or (http://example.com/a_(b)), <https://example.com/x> and [y](https://example.com/y).\r\n";
+    let message = Message::parse(raw).unwrap();
+    let options = HtmlOptions {
+        linkify: true,
+        ..HtmlOptions::default()
+    };
+    let html = text_body_as_html_fragment(&message, &options);
+
+    assert!(html.contains(r#"<a href="https://example.com/docs">https://example.com/docs</a>."#));
+    assert!(html.contains(r#"(<a href="http://example.com/a_(b)">http://example.com/a_(b)</a>),"#));
+    assert!(html.contains(r#"<a href="https://example.com/x">https://example.com/x</a> and"#));
+    assert!(html.contains(r#"<a href="https://example.com/y">y</a>."#));
+}
+
+#[test]
+fn linkify_skips_urls_in_code() {
+    let raw = b"Subject: Code\r\n\r\n```\r\ncurl https://example.com/a\r\n```\r\n\r\n    curl https://example.com/b\r\n\r\nRun `curl https://example.com/c` or https://example.com/d\r\n";
+    let message = Message::parse(raw).unwrap();
+    let options = HtmlOptions {
+        linkify: true,
+        ..HtmlOptions::default()
+    };
+    let html = text_body_as_html_fragment(&message, &options);
+
+    assert!(html.contains("<code>curl https://example.com/a"));
+    assert!(html.contains("<code>curl https://example.com/b"));
+    assert!(html.contains("<code>curl https://example.com/c</code>"));
+    assert!(html.contains(r#"<a href="https://example.com/d">https://example.com/d</a>"#));
+    assert!(!html.contains("&lt;https"));
+}
+
+#[test]
+fn emoji_skips_shortcodes_in_code() {
+    let raw = b"Subject: Code\r\n\r\nDone :tada:\r\n\r\n```\r\nhost:8080:tada:\r\n```\r\n\r\nSee `:tada:`\r\n";
+    let message = Message::parse(raw).unwrap();
+    let options = HtmlOptions {
+        emoji: true,
+        ..HtmlOptions::default()
+    };
+    let html = text_body_as_html_fragment(&message, &options);
+
+    assert!(html.contains("Done 🎉"));
+    assert!(html.contains("host:8080:tada:"));
+    assert!(html.contains("<code>:tada:</code>"));
+}
+
+#[test]
+fn md_flags_enable_extensions() {
+    let raw = b"Subject: Ext\r\n\r\n~~old~~ new\r\n";
+    let message = Message::parse(raw).unwrap();
+    let html = text_body_as_html_fragment(&message, &HtmlOptions::default());
+    assert!(!html.contains("<del>"));
+
+    let options = HtmlOptions {
+        md_flags: vec![MarkdownExtension::Strikethrough],
+        ..HtmlOptions::default()
+    };
+    let html = text_body_as_html_fragment(&message, &options);
+    assert!(html.contains("<del>old</del> new"));
+}
+
+#[test]
+fn strip_html_comments_from_original_html() {
+    let content = fixture("html_comment.eml");
+    let message = Message::parse(&content).unwrap();
+    let options = RebuildOptions {
+        keep_original_html: true,
+        strip_html_comments: true,
+        ..RebuildOptions::default()
+    };
+    let output = get_builder_from_parser(&message, &options)
+        .write_to_vec()
+        .unwrap();
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert_eq!(
+        rebuilt.body_html(0).unwrap().trim_end(),
+        "<p>Hello reader.</p>"
+    );
+}
+
+#[test]
+fn strip_html_comments_from_generated_html() {
+    let content = fixture("html_comment.eml");
+    let message = Message::parse(&content).unwrap();
+    let commented = text_body_as_html_fragment(&message, &HtmlOptions::default());
+    let html = text_body_as_html_fragment(
+        &message,
+        &HtmlOptions {
+            strip_comments: true,
+            ..HtmlOptions::default()
+        },
+    );
+
+    assert!(commented.contains("<!--"));
+    assert_eq!(html.trim_end(), "<p>Hello  reader.</p>");
+}
+
+#[test]
+fn generated_html_is_alternative_to_text() {
+    let content = fixture("plain.eml");
+    let message = Message::parse(&content).unwrap();
+    let html = text_body_as_html(&message, None, &HtmlOptions::default()).unwrap();
+    let output = get_builder_from_parser(&message, &RebuildOptions::default())
+        .html_body(html)
+        .write_to_vec()
+        .unwrap();
+    let rebuilt = Message::parse(&output).unwrap();
+
+    assert_eq!(get_message_content_type(&rebuilt), "multipart/alternative");
+    assert_eq!(
+        rebuilt.body_text(0).unwrap().trim_end(),
+        "Hello *world*, this is a paragraph."
+    );
+    assert!(rebuilt
+        .body_html(0)
+        .unwrap()
+        .contains("<em>world</em>, this is a paragraph."));
+}
+
+const NESTED_REPLY: &str = "Sounds good.\n\n\
+On Mon, 3 Apr 2023, Bob wrote:\n\
+> Can we meet?\n\
+>\n\
+> On Sun, 2 Apr 2023, Alice <alice@example.com>\n\
+> wrote:\n\
+> > Lunch on Monday?\n\
+> > > Earlier thread\n";
+
+#[test]
+fn strip_quotes_removes_nested_levels_and_attributions() {
+    assert_eq!(strip_quotes(NESTED_REPLY, 0), "Sounds good.\n");
+}
+
+#[test]
+fn strip_quotes_keeps_one_level() {
+    assert_eq!(
+        strip_quotes(NESTED_REPLY, 1),
+        "Sounds good.\n\nOn Mon, 3 Apr 2023, Bob wrote:\n> Can we meet?\n>\n"
+    );
+}
+
+#[test]
+fn strip_quotes_keeps_unquoted_attribution_like_text() {
+    let text = "On Monday I wrote:\nthe report.\n";
+    assert_eq!(strip_quotes(text, 0), text);
+}
+
+#[test]
+fn pixel_with_fallback_has_both_images() {
+    let content = fixture("plain.eml");
+    let message = Message::parse(&content).unwrap();
+    let pixel = get_pixel_element(
+        &["https://track.example.com", "https://mail.example.com"],
+        message.message_id().unwrap(),
+    );
+
+    assert_eq!(pixel.matches("<img").count(), 2);
+    assert!(
+        pixel.find("https://track.example.com").unwrap()
+            < pixel.find("https://mail.example.com").unwrap()
+    );
+}
+
+#[test]
+fn inline_css_styles_code_elements() {
+    let content = fixture("code.eml");
+    let message = Message::parse(&content).unwrap();
+    let options = HtmlOptions {
+        inline_css: true,
+        ..HtmlOptions::default()
+    };
+    let html = text_body_as_html(&message, None, &options).unwrap();
+
+    assert!(html.contains("<code style=\""));
+    assert!(html.contains("font-family: monospace;"));
+    assert!(!text_body_as_html(&message, None, &HtmlOptions::default())
+        .unwrap()
+        .contains("<code style="));
+}
+
+#[test]
+fn oversized_html_is_rejected_with_abort() {
+    let content = fixture("plain.eml");
+    let message = Message::parse(&content).unwrap();
+    let options = HtmlOptions {
+        max_size: Some(100),
+        abort_oversized: true,
+        ..HtmlOptions::default()
+    };
+    let html = text_body_as_html(&message, None, &options).unwrap();
+
+    assert!(html.contains("<em>world</em>"));
+    assert!(check_html_size(&html, &options).is_err());
+}
+
+#[test]
+fn detect_language_returns_iso_639_1_code() {
+    let polish = fixture("polish.eml");
+    let plain = fixture("plain.eml");
+
+    assert_eq!(
+        detect_language(&Message::parse(&polish).unwrap(), &BodyOptions::default()),
+        "pl"
+    );
+    assert_eq!(
+        detect_language(&Message::parse(&plain).unwrap(), &BodyOptions::default()),
+        "und"
+    );
+}
+
+#[test]
+fn detect_language_without_text_part_is_undetermined() {
+    let content = fixture("attachment_only.eml");
+    let message = Message::parse(&content).unwrap();
+
+    assert_eq!(detect_language(&message, &BodyOptions::default()), "und");
+    assert!(text_body_as_html(&message, None, &HtmlOptions::default())
+        .unwrap()
+        .contains("<body>"));
+}
@@ -0,0 +1,29 @@
+use enrichmail::expand_mailbox_template;
+use mail_parser::Message;
+
+mod common;
+use common::fixture;
+
+#[test]
+fn mailbox_template_expands_message_date() {
+    let content = fixture("plain.eml");
+    let message = Message::parse(&content).unwrap();
+
+    assert_eq!(
+        expand_mailbox_template("Archive/{YYYY}", Some(&message)),
+        "Archive/2023"
+    );
+    assert_eq!(
+        expand_mailbox_template("Archive/{YYYY}/{MM}/{DD}", Some(&message)),
+        "Archive/2023/05/02"
+    );
+}
+
+#[test]
+fn mailbox_template_without_date_uses_current_year() {
+    let message = Message::parse(b"Subject: Undated\r\n\r\nbody\r\n").unwrap();
+    let expanded = expand_mailbox_template("Archive/{YYYY}", Some(&message));
+
+    let year: u16 = expanded.strip_prefix("Archive/").unwrap().parse().unwrap();
+    assert!(year >= 2023);
+}
@@ -0,0 +1,27 @@
+use enrichmail::{escape_mbox_content, get_mbox_separator, split_mbox};
+use mail_parser::Message;
+
+mod common;
+use common::fixture;
+
+#[test]
+fn mbox_separator_uses_sender_and_date() {
+    let content = fixture("plain.eml");
+    let message = Message::parse(&content).unwrap();
+
+    assert_eq!(
+        get_mbox_separator(&message),
+        "From alice@example.com Tue May  2 10:00:00 2023"
+    );
+}
+
+#[test]
+fn escaped_output_splits_back_into_original() {
+    let content = "Subject: Hi\r\n\r\nFrom the start\r\n>From quoted\r\n";
+    let mut mbox = b"From a@example.com Tue May  2 10:00:00 2023\n".to_vec();
+    mbox.extend(escape_mbox_content(content.as_bytes()));
+
+    let messages = split_mbox(&mbox);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].1, content.as_bytes());
+}
@@ -0,0 +1,31 @@
+use std::process::Command;
+
+mod common;
+use common::fixture;
+
+fn run(args: &[&str], name: &str) -> Vec<u8> {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let output = Command::new(env!("CARGO_BIN_EXE_enrichmail"))
+        .args(args)
+        .arg(path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    output.stdout
+}
+
+#[test]
+fn preserve_structure_outputs_input_verbatim() {
+    assert_eq!(
+        run(&["--preserve-structure"], "plain.eml"),
+        fixture("plain.eml")
+    );
+}
+
+#[test]
+fn passthrough_signed_outputs_input_verbatim() {
+    assert_eq!(
+        run(&["--passthrough-signed"], "signed.eml"),
+        fixture("signed.eml")
+    );
+}